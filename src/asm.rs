@@ -0,0 +1,428 @@
+//! A textual form of compiled Yarn [Program]s.
+//!
+//! [`disassemble`] prints a program one instruction per line, and [`assemble`] parses the same
+//! text back into a [Program] that compares equal to the original:
+//!
+//! ```text
+//! .program "sally"
+//! .initial "$sally_warning" false
+//!
+//! .node "Sally"
+//! .tags "sally"
+//! .header "title" "Sally"
+//! "L1":
+//!     0000 PUSH_STRING "Sally"
+//!     0001 PUSH_FLOAT 1.0
+//!     0002 CALL_FUNC "visited"
+//! ```
+//!
+//! Labels are written before the instruction they point at. Instruction offsets are optional
+//! when assembling, and anything following a `;` outside of a string is a comment.
+//!
+//! The one exception to the round trip is a NaN float operand, which is written as `NaN` and
+//! assembled back into a NaN that, like any NaN, does not compare equal to the original.
+
+use std::fmt::Write;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use thiserror::Error;
+
+use crate::model::{operand, Header, Instruction, Node, OpCode, Operand, Program};
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum AssembleError {
+    #[error("line {0}: unterminated string")]
+    UnterminatedString(usize),
+
+    #[error("line {0}: invalid escape sequence '\\{1}'")]
+    InvalidEscape(usize, String),
+
+    #[error("line {0}: unknown directive '{1}'")]
+    UnknownDirective(usize, String),
+
+    #[error("line {0}: unknown opcode '{1}'")]
+    UnknownOpcode(usize, String),
+
+    #[error("line {0}: invalid operand '{1}'")]
+    InvalidOperand(usize, String),
+
+    #[error("line {0}: expected {1}")]
+    Expected(usize, &'static str),
+
+    #[error("line {0}: instruction offset {1} does not match its position {2}")]
+    OffsetMismatch(usize, usize, usize),
+
+    #[error("line {0}: instruction or label found outside of a node")]
+    OutsideNode(usize),
+
+    #[error("line {0}: duplicate node '{1}'")]
+    DuplicateNode(usize, String),
+}
+
+/// Print every node of [program], sorted by name, along with its initial values.
+#[must_use]
+pub fn disassemble(program: &Program) -> String {
+    let mut text = String::new();
+
+    let _ = writeln!(text, ".program {}", quote(&program.name));
+
+    let mut initial_values: Vec<_> = program.initial_values.iter().collect();
+    initial_values.sort_by_key(|(name, _)| *name);
+    for (name, value) in initial_values {
+        let _ = writeln!(text, ".initial {} {}", quote(name), format_operand(value));
+    }
+
+    let mut nodes: Vec<_> = program.nodes.iter().collect();
+    nodes.sort_by_key(|(key, _)| *key);
+    for (key, node) in nodes {
+        text.push('\n');
+
+        // Nodes are keyed by their name in every compiled program, but keep any that aren't.
+        if *key != node.name {
+            let _ = writeln!(text, ".key {}", quote(key));
+        }
+
+        text.push_str(&disassemble_node(node));
+    }
+
+    text
+}
+
+/// Print the instructions of [node] with their offsets and labels.
+#[must_use]
+pub fn disassemble_node(node: &Node) -> String {
+    let mut text = String::new();
+
+    let _ = writeln!(text, ".node {}", quote(&node.name));
+
+    if !node.tags.is_empty() {
+        let tags: Vec<_> = node.tags.iter().map(|tag| quote(tag)).collect();
+        let _ = writeln!(text, ".tags {}", tags.join(" "));
+    }
+
+    if !node.source_text_string_id.is_empty() {
+        let _ = writeln!(text, ".source {}", quote(&node.source_text_string_id));
+    }
+
+    for header in &node.headers {
+        let _ = writeln!(
+            text,
+            ".header {} {}",
+            quote(&header.key),
+            quote(&header.value)
+        );
+    }
+
+    let mut labels: Vec<_> = node.labels.iter().collect();
+    labels.sort_by(|(a_name, a_pc), (b_name, b_pc)| a_pc.cmp(b_pc).then(a_name.cmp(b_name)));
+
+    // Labels that can't be placed before an instruction are given with an explicit offset.
+    let count = node.instructions.len();
+    let (labels, unplaced): (Vec<_>, Vec<_>) = labels
+        .into_iter()
+        .partition(|(_, pc)| matches!(usize::try_from(**pc), Ok(pc) if pc <= count));
+
+    for (name, pc) in unplaced {
+        let _ = writeln!(text, ".label {} {pc}", quote(name));
+    }
+
+    let mut labels = labels.into_iter().peekable();
+
+    for pc in 0..=count {
+        while let Some((name, _)) = labels.next_if(|(_, label)| **label as usize == pc) {
+            let _ = writeln!(text, "{}:", quote(name));
+        }
+
+        let Some(instruction) = node.instructions.get(pc) else {
+            break;
+        };
+
+        let opcode = OpCode::from_i32(instruction.opcode).map_or_else(
+            || format!("#{}", instruction.opcode),
+            |opcode| opcode.as_str_name().to_string(),
+        );
+
+        let _ = write!(text, "    {pc:04} {opcode}");
+        for operand in &instruction.operands {
+            let _ = write!(text, " {}", format_operand(operand));
+        }
+
+        text.push('\n');
+    }
+
+    text
+}
+
+/// Parse text in the format produced by [`disassemble`] into a [Program].
+///
+/// # Errors
+///
+/// Returns `Err` if the text is malformed, naming the line the problem was found on.
+pub fn assemble(text: &str) -> Result<Program, AssembleError> {
+    let mut program = Program::default();
+    let mut current: Option<(String, Node)> = None;
+    let mut key: Option<String> = None;
+
+    let finish = |current: Option<(String, Node)>, program: &mut Program, line: usize| {
+        if let Some((key, node)) = current {
+            if program.nodes.contains_key(&key) {
+                return Err(AssembleError::DuplicateNode(line, key));
+            }
+
+            program.nodes.insert(key, node);
+        }
+
+        Ok(())
+    };
+
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        let tokens = tokenize(line, number)?;
+        let mut tokens = tokens.into_iter().peekable();
+
+        let Some(first) = tokens.next() else {
+            continue;
+        };
+
+        let node = current.as_mut().map(|(_, node)| node);
+
+        match first {
+            Token::Word(word) if word.starts_with('.') => {
+                let mut string = |what| expect_string(&mut tokens, number, what);
+
+                match word.as_str() {
+                    ".program" => program.name = string("a program name")?,
+                    ".initial" => {
+                        let name = string("a variable name")?;
+                        let value = tokens
+                            .next()
+                            .ok_or(AssembleError::Expected(number, "an initial value"))
+                            .and_then(|token| parse_operand(token, number))?;
+
+                        program.initial_values.insert(name, value);
+                    }
+                    ".key" => key = Some(string("a node key")?),
+                    ".node" => {
+                        finish(current.take(), &mut program, number)?;
+
+                        let name = string("a node name")?;
+                        let node = Node {
+                            name: name.clone(),
+                            ..Node::default()
+                        };
+
+                        current = Some((key.take().unwrap_or(name), node));
+                    }
+                    ".tags" => {
+                        let node = node.ok_or(AssembleError::OutsideNode(number))?;
+                        while tokens.peek().is_some() {
+                            node.tags.push(expect_string(&mut tokens, number, "a tag")?);
+                        }
+                    }
+                    ".source" => {
+                        let node = node.ok_or(AssembleError::OutsideNode(number))?;
+                        node.source_text_string_id = string("a string ID")?;
+                    }
+                    ".header" => {
+                        let node = node.ok_or(AssembleError::OutsideNode(number))?;
+                        let key = string("a header key")?;
+                        let value = string("a header value")?;
+
+                        node.headers.push(Header { key, value });
+                    }
+                    ".label" => {
+                        let node = node.ok_or(AssembleError::OutsideNode(number))?;
+                        let name = string("a label name")?;
+                        let offset = match tokens.next() {
+                            Some(Token::Word(offset)) => offset
+                                .parse()
+                                .map_err(|_| AssembleError::InvalidOperand(number, offset))?,
+                            _ => return Err(AssembleError::Expected(number, "a label offset")),
+                        };
+
+                        node.labels.insert(name, offset);
+                    }
+                    _ => return Err(AssembleError::UnknownDirective(number, word)),
+                }
+            }
+            Token::String(label) => {
+                let node = node.ok_or(AssembleError::OutsideNode(number))?;
+                if !matches!(tokens.next(), Some(Token::Colon)) {
+                    return Err(AssembleError::Expected(number, "':' after a label"));
+                }
+
+                node.labels.insert(label, node.instructions.len() as i32);
+            }
+            Token::Word(mut word) => {
+                let node = node.ok_or(AssembleError::OutsideNode(number))?;
+                let pc = node.instructions.len();
+
+                if word.bytes().all(|b| b.is_ascii_digit()) {
+                    let offset: usize = word
+                        .parse()
+                        .map_err(|_| AssembleError::InvalidOperand(number, word.clone()))?;
+
+                    if offset != pc {
+                        return Err(AssembleError::OffsetMismatch(number, offset, pc));
+                    }
+
+                    word = match tokens.next() {
+                        Some(Token::Word(word)) => word,
+                        _ => return Err(AssembleError::Expected(number, "an opcode")),
+                    };
+                }
+
+                let opcode = match word.strip_prefix('#') {
+                    Some(raw) => raw.parse().ok(),
+                    None => OpCode::from_str_name(&word).map(|opcode| opcode as i32),
+                }
+                .ok_or_else(|| AssembleError::UnknownOpcode(number, word.clone()))?;
+
+                let operands = tokens
+                    .map(|token| parse_operand(token, number))
+                    .collect::<Result<_, _>>()?;
+
+                node.instructions.push(Instruction { opcode, operands });
+            }
+            Token::Colon => return Err(AssembleError::Expected(number, "a label name")),
+        }
+    }
+
+    finish(current, &mut program, text.lines().count())?;
+
+    Ok(program)
+}
+
+fn expect_string(
+    tokens: &mut impl Iterator<Item = Token>,
+    number: usize,
+    what: &'static str,
+) -> Result<String, AssembleError> {
+    match tokens.next() {
+        Some(Token::String(value)) => Ok(value),
+        _ => Err(AssembleError::Expected(number, what)),
+    }
+}
+
+#[derive(Debug)]
+enum Token {
+    Word(String),
+    String(String),
+    Colon,
+}
+
+fn tokenize(line: &str, number: usize) -> Result<Vec<Token>, AssembleError> {
+    let mut tokens = vec![];
+    let mut chars = line.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            ';' => break,
+            ':' => {
+                chars.next();
+                tokens.push(Token::Colon);
+            }
+            '"' => {
+                chars.next();
+                tokens.push(Token::String(unquote(&mut chars, number)?));
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !matches!(c, ';' | ':'))
+                {
+                    word.push(c);
+                }
+
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Read the rest of a string literal whose opening quote has already been consumed.
+fn unquote(chars: &mut Peekable<Chars>, number: usize) -> Result<String, AssembleError> {
+    let mut value = String::new();
+
+    loop {
+        match chars.next() {
+            None => return Err(AssembleError::UnterminatedString(number)),
+            Some('"') => return Ok(value),
+            Some('\\') => match chars.next() {
+                Some('"') => value.push('"'),
+                Some('\\') => value.push('\\'),
+                Some('n') => value.push('\n'),
+                Some('r') => value.push('\r'),
+                Some('t') => value.push('\t'),
+                Some('u') => {
+                    let code: String = chars.by_ref().take(4).collect();
+                    let c = u32::from_str_radix(&code, 16)
+                        .ok()
+                        .filter(|_| code.len() == 4)
+                        .and_then(char::from_u32)
+                        .ok_or_else(|| AssembleError::InvalidEscape(number, format!("u{code}")))?;
+
+                    value.push(c);
+                }
+                Some(c) => return Err(AssembleError::InvalidEscape(number, c.to_string())),
+                None => return Err(AssembleError::UnterminatedString(number)),
+            },
+            Some(c) => value.push(c),
+        }
+    }
+}
+
+fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() && (c as u32) <= 0xFFFF => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+fn format_operand(operand: &Operand) -> String {
+    match &operand.value {
+        Some(operand::Value::StringValue(value)) => quote(value),
+        Some(operand::Value::BoolValue(value)) => value.to_string(),
+        // Debug formatting of floats is the shortest form that parses back to the same value.
+        Some(operand::Value::FloatValue(value)) => format!("{value:?}"),
+        None => "null".to_string(),
+    }
+}
+
+fn parse_operand(token: Token, number: usize) -> Result<Operand, AssembleError> {
+    let value = match token {
+        Token::String(value) => Some(operand::Value::StringValue(value)),
+        Token::Word(word) => match word.as_str() {
+            "true" => Some(operand::Value::BoolValue(true)),
+            "false" => Some(operand::Value::BoolValue(false)),
+            "null" => None,
+            _ => Some(operand::Value::FloatValue(
+                word.parse()
+                    .map_err(|_| AssembleError::InvalidOperand(number, word))?,
+            )),
+        },
+        Token::Colon => return Err(AssembleError::InvalidOperand(number, ":".to_string())),
+    };
+
+    Ok(Operand { value })
+}
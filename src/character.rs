@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+
+use crate::line::ResolvedLine;
+
+/// Presentation details of a character that speaks lines in a story.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Character {
+    /// The name shown to the player, which may differ from the name used in the story's text.
+    pub display_name: Option<String>,
+
+    /// An identifier for the portrait shown alongside the character's lines.
+    pub portrait: Option<String>,
+
+    /// An identifier for the voice used for the character's lines.
+    pub voice: Option<String>,
+}
+
+impl Character {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn with_display_name<S: Into<String>>(mut self, display_name: S) -> Self {
+        self.display_name = Some(display_name.into());
+        self
+    }
+
+    #[must_use]
+    pub fn with_portrait<S: Into<String>>(mut self, portrait: S) -> Self {
+        self.portrait = Some(portrait.into());
+        self
+    }
+
+    #[must_use]
+    pub fn with_voice<S: Into<String>>(mut self, voice: S) -> Self {
+        self.voice = Some(voice.into());
+        self
+    }
+}
+
+/// Maps the names of speakers to metadata about them, which is a [Character] unless another
+/// type is given.
+#[derive(Clone, Debug)]
+pub struct CharacterRegistry<C = Character> {
+    characters: HashMap<String, C>,
+}
+
+impl<C> Default for CharacterRegistry<C> {
+    fn default() -> Self {
+        Self {
+            characters: HashMap::new(),
+        }
+    }
+}
+
+impl<C> CharacterRegistry<C> {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register [character] as the speaker named [name], replacing any character previously
+    /// registered with that name.
+    pub fn register<S: Into<String>>(&mut self, name: S, character: C) -> &mut Self {
+        self.characters.insert(name.into(), character);
+        self
+    }
+
+    /// Check if a character is registered as the speaker named [name].
+    pub fn contains<S: AsRef<str>>(&self, name: S) -> bool {
+        self.characters.contains_key(name.as_ref())
+    }
+
+    pub fn get<S: AsRef<str>>(&self, name: S) -> Option<&C> {
+        self.characters.get(name.as_ref())
+    }
+
+    /// Get the character speaking [line], if it has a speaker and they are registered.
+    #[must_use]
+    pub fn speaker(&self, line: &ResolvedLine) -> Option<&C> {
+        self.get(line.speaker.as_deref()?)
+    }
+
+    /// Iterate over the names of all registered characters alongside their metadata.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &C)> {
+        self.characters
+            .iter()
+            .map(|(name, character)| (name.as_str(), character))
+    }
+}
@@ -0,0 +1,148 @@
+use thiserror::Error;
+
+use crate::function::{CallContext, CallError, Function, Library};
+use crate::model::Value;
+
+/// A set of commands that can be dispatched automatically when a story runs them.
+///
+/// Command handlers are registered in the same way as [`Library`] functions: they receive a
+/// [`CallContext`] followed by typed parameters converted from the command's arguments.
+pub struct CommandRegistry {
+    handlers: Library,
+}
+
+#[derive(Error, Debug)]
+pub enum CommandError {
+    #[error(transparent)]
+    Call(#[from] CallError),
+}
+
+impl CommandRegistry {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            handlers: Library::new(),
+        }
+    }
+
+    pub fn register<Marker, F, S: Into<String>>(&mut self, name: S, handler: F)
+    where
+        F: Function<Marker> + 'static,
+        Marker: 'static,
+    {
+        self.handlers.register(name, handler);
+    }
+
+    /// Check if a handler for the command with the given [`name`] has been registered.
+    #[must_use]
+    pub fn contains(&self, name: &str) -> bool {
+        self.handlers.contains(name)
+    }
+
+    /// Parse [`text`] and run the handler for the command it names, if one is registered.
+    ///
+    /// Returns `Ok(false)` if the text is empty or there is no handler for the command, leaving
+    /// it to the caller.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the arguments do not match the handler's parameters.
+    pub fn dispatch(&self, context: CallContext, text: &str) -> Result<bool, CommandError> {
+        let mut tokens = tokenize(text).into_iter();
+        let name = match tokens.next() {
+            Some(name) if self.handlers.contains(&name.text) => name,
+            _ => return Ok(false),
+        };
+
+        let args = tokens.map(Token::into_value).collect();
+        self.handlers.call(&name.text, context, args)?;
+
+        Ok(true)
+    }
+}
+
+impl Default for CommandRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A single argument of a command.
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+impl Token {
+    /// Convert this token into a [`Value`], treating unquoted numbers and booleans as such and
+    /// everything else as a string.
+    ///
+    /// Only numbers written with digits count, so words such as `inf` and `nan` stay strings.
+    fn into_value(self) -> Value {
+        if !self.quoted {
+            let digits = self.text.trim_start_matches(['+', '-']);
+
+            if matches!(digits.chars().next(), Some('0'..='9' | '.')) {
+                if let Ok(value) = self.text.parse::<f32>() {
+                    return Value::FloatValue(value);
+                }
+            }
+
+            match self.text.as_str() {
+                "true" => return Value::BoolValue(true),
+                "false" => return Value::BoolValue(false),
+                _ => {}
+            }
+        }
+
+        Value::StringValue(self.text)
+    }
+}
+
+/// Split command text into its components using Yarn's quoting rules.
+///
+/// Components are separated by whitespace. Text enclosed in double quotes is part of a single
+/// component, within which `\"` and `\\` escape a quote and a backslash respectively. Quoted
+/// text that directly follows or precedes other text is joined with it, so `a"b c"d` is the
+/// single component `ab cd`.
+#[must_use]
+pub fn split_command_text(text: &str) -> Vec<String> {
+    tokenize(text).into_iter().map(|token| token.text).collect()
+}
+
+fn tokenize(text: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut current: Option<Token> = None;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            tokens.extend(current.take());
+            continue;
+        }
+
+        let token = current.get_or_insert_with(|| Token {
+            text: String::new(),
+            quoted: false,
+        });
+
+        if c == '"' {
+            token.quoted = true;
+
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' if matches!(chars.peek(), Some('\\' | '"')) => {
+                        token.text.extend(chars.next());
+                    }
+                    '"' => break,
+                    _ => token.text.push(c),
+                }
+            }
+        } else {
+            token.text.push(c);
+        }
+    }
+
+    tokens.extend(current);
+    tokens
+}
@@ -1,25 +1,33 @@
+use std::any::{type_name, Any};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::vec::IntoIter;
 use std::{collections::HashMap, marker::PhantomData};
-use std::any::type_name;
 
 use thiserror::Error;
 
 use crate::{
     model::{Node, Value, ValueError},
-    story::Story,
+    runner::Rng,
+    story::{Op, Story},
     variables::VariableStore,
 };
 
 mod builtins;
 
 pub struct Library {
-    functions: HashMap<String, Box<dyn UntypedFunction>>,
+    functions: Vec<Box<dyn UntypedFunction>>,
+
+    /// Index into [functions] of each function by name.
+    indices: HashMap<String, usize>,
 }
 
 impl Library {
     #[must_use]
     pub fn new() -> Self {
         Self {
-            functions: HashMap::default(),
+            functions: Vec::new(),
+            indices: HashMap::default(),
         }
     }
 
@@ -27,12 +35,66 @@ impl Library {
         let mut library = Self::new();
         library.register("visited", builtins::visited);
         library.register("visited_count", builtins::visited_count);
+        library.register("random", builtins::random);
+        library.register("random_range", builtins::random_range);
+        library.register("dice", builtins::dice);
         library.register("floor", |_ctx: CallContext, a: f32| a.floor());
         library.register("ceil", |_ctx: CallContext, a: f32| a.ceil());
+        library.register("round", builtins::round);
+        library.register("round_places", builtins::round_places);
+        library.register("truncate", |_ctx: CallContext, a: f32| a.trunc());
+        library.register("int", |_ctx: CallContext, a: f32| a.trunc());
+        library.register("decimal", builtins::decimal);
+        library.register("inc", builtins::inc);
+        library.register("dec", builtins::dec);
+        library.register("string", |_ctx: CallContext, a: String| a);
+        library.register("number", builtins::number);
+        library.register("bool", builtins::bool);
+        library.register("format_invariant", builtins::format_invariant);
+
         library.register("Bool.EqualTo", |_ctx: CallContext, a: bool, b: bool| a == b);
+        library.register("Bool.NotEqualTo", |_ctx: CallContext, a: bool, b: bool| {
+            a != b
+        });
         library.register("Bool.Not", |_ctx: CallContext, a: bool| !a);
         library.register("Bool.And", |_ctx: CallContext, a: bool, b: bool| a && b);
+        library.register("Bool.Or", |_ctx: CallContext, a: bool, b: bool| a || b);
+        library.register("Bool.Xor", |_ctx: CallContext, a: bool, b: bool| a ^ b);
+
+        library.register("Number.EqualTo", |_ctx: CallContext, a: f32, b: f32| a == b);
+        library.register("Number.NotEqualTo", |_ctx: CallContext, a: f32, b: f32| {
+            a != b
+        });
+        library.register("Number.GreaterThan", |_ctx: CallContext, a: f32, b: f32| {
+            a > b
+        });
+        library.register(
+            "Number.GreaterThanOrEqualTo",
+            |_ctx: CallContext, a: f32, b: f32| a >= b,
+        );
+        library.register("Number.LessThan", |_ctx: CallContext, a: f32, b: f32| a < b);
+        library.register(
+            "Number.LessThanOrEqualTo",
+            |_ctx: CallContext, a: f32, b: f32| a <= b,
+        );
         library.register("Number.Add", |_ctx: CallContext, a: f32, b: f32| a + b);
+        library.register("Number.Minus", |_ctx: CallContext, a: f32, b: f32| a - b);
+        library.register("Number.Multiply", |_ctx: CallContext, a: f32, b: f32| a * b);
+        library.register("Number.Divide", |_ctx: CallContext, a: f32, b: f32| a / b);
+        library.register("Number.Modulo", builtins::modulo);
+        library.register("Number.UnaryMinus", |_ctx: CallContext, a: f32| -a);
+
+        library.register(
+            "String.EqualTo",
+            |_ctx: CallContext, a: String, b: String| a == b,
+        );
+        library.register(
+            "String.NotEqualTo",
+            |_ctx: CallContext, a: String, b: String| a != b,
+        );
+        library.register("String.Add", |_ctx: CallContext, a: String, b: String| {
+            a + &b
+        });
         library
     }
 
@@ -43,15 +105,87 @@ impl Library {
     /// - [`CallError::InvalidArguments`] if the arguments passed do not match the call signature.
     pub fn call(
         &self,
-        name: String,
+        name: &str,
+        context: CallContext,
+        args: Vec<Value>,
+    ) -> Result<Value, CallError> {
+        self.index(name).map_or_else(
+            || Err(CallError::UnknownFunction(name.to_string())),
+            |index| self.call_at(index, context, args),
+        )
+    }
+
+    /// Get the index of the function with the given [`name`], which stays the same for as long
+    /// as the library exists.
+    pub(crate) fn index(&self, name: &str) -> Option<usize> {
+        self.indices.get(name).copied()
+    }
+
+    /// Call the function at [`index`], as given by [`Library::index`], with the given [`args`].
+    pub(crate) fn call_at(
+        &self,
+        index: usize,
         context: CallContext,
         args: Vec<Value>,
     ) -> Result<Value, CallError> {
-        self.functions
-            .get(&name)
-            .map_or(Err(CallError::UnknownFunction(name)), |function| {
-                function.call(context, args)
-            })
+        self.functions[index].call(context, args)
+    }
+
+    /// Check if a function with the given [`name`] has been registered.
+    #[must_use]
+    pub fn contains(&self, name: &str) -> bool {
+        self.indices.contains_key(name)
+    }
+
+    /// Get the signature of the function with the given [`name`], if one has been registered.
+    #[must_use]
+    pub fn signature(&self, name: &str) -> Option<&Signature> {
+        self.index(name)
+            .map(|index| self.functions[index].signature())
+    }
+
+    /// Check every function call made by [story] against the functions in this library.
+    ///
+    /// Calls to functions that have not been registered, and calls with a number of arguments
+    /// the function does not accept, are reported in the order they appear in the story.
+    #[must_use]
+    pub fn check(&self, story: &Story) -> Vec<LinkError> {
+        let mut errors = vec![];
+
+        for node in story.compiled_nodes() {
+            for (pc, op) in node.ops.iter().enumerate() {
+                let Op::CallFunc(name) = op else {
+                    continue;
+                };
+
+                let name = story.string(*name);
+                let Some(signature) = self.signature(name) else {
+                    errors.push(LinkError::UnknownFunction {
+                        node: node.name().to_string(),
+                        pc,
+                        name: name.to_string(),
+                    });
+                    continue;
+                };
+
+                // The argument count is pushed as a constant immediately before the call.
+                if let Some(Op::PushFloat(count)) = pc.checked_sub(1).map(|prev| node.ops[prev]) {
+                    let count = count as usize;
+
+                    if !signature.arity.accepts(count) {
+                        errors.push(LinkError::InvalidArgumentCount {
+                            node: node.name().to_string(),
+                            pc,
+                            name: name.to_string(),
+                            expected: signature.arity,
+                            found: count,
+                        });
+                    }
+                }
+            }
+        }
+
+        errors
     }
 
     pub fn register<Marker, F, S: Into<String>>(&mut self, name: S, function: F)
@@ -61,10 +195,18 @@ impl Library {
     {
         let handle = FunctionHandle {
             function,
+            signature: F::signature(),
             marker: PhantomData::default(),
         };
 
-        self.functions.insert(name.into(), Box::new(handle));
+        let name = name.into();
+        match self.indices.get(&name).copied() {
+            Some(index) => self.functions[index] = Box::new(handle),
+            None => {
+                self.indices.insert(name, self.functions.len());
+                self.functions.push(Box::new(handle));
+            }
+        }
     }
 }
 
@@ -83,18 +225,253 @@ pub enum CallError {
     InvalidArguments(&'static str, Value),
 
     #[error("invalid argument count, expected {0}, found {1}")]
-    InvalidArgumentCount(usize, usize)
+    InvalidArgumentCount(Arity, usize),
+
+    /// A registered function reported an error of its own.
+    #[error("function failed: {0}")]
+    User(#[source] Box<dyn Error + Send + Sync>),
+
+    /// The user data given to the runner is not of the type a function asked for with
+    /// [`CallContext::try_user_data_mut`].
+    #[error("user data is not of type {0}")]
+    UserDataType(&'static str),
+}
+
+/// A problem found by [`Library::check`] with a function call made by a [Story].
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    #[error("node '{node}' calls unknown function '{name}' at #{pc}")]
+    UnknownFunction {
+        node: String,
+        pc: usize,
+        name: String,
+    },
+
+    #[error("node '{node}' calls '{name}' with {found} arguments at #{pc}, expected {expected}")]
+    InvalidArgumentCount {
+        node: String,
+        pc: usize,
+        name: String,
+        expected: Arity,
+        found: usize,
+    },
+}
+
+/// The parameter and return types of a registered [`Function`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Signature {
+    /// The type name of each parameter, excluding the [`CallContext`].
+    pub parameters: Vec<&'static str>,
+
+    /// The type name of the value returned by the function.
+    pub returns: &'static str,
+
+    pub arity: Arity,
+}
+
+impl Display for Signature {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({}) -> {}", self.parameters.join(", "), self.returns)
+    }
+}
+
+/// The number of arguments accepted by a [`Function`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Arity {
+    pub min: usize,
+
+    /// The maximum number of arguments, or [`None`] if any number of additional arguments is
+    /// accepted.
+    pub max: Option<usize>,
+}
+
+impl Arity {
+    #[must_use]
+    pub const fn exactly(count: usize) -> Self {
+        Self {
+            min: count,
+            max: Some(count),
+        }
+    }
+
+    /// The arity of a function taking the parameters of both [self] and [other].
+    #[must_use]
+    pub const fn then(self, other: Self) -> Self {
+        Self {
+            min: self.min + other.min,
+            max: match (self.max, other.max) {
+                (Some(a), Some(b)) => Some(a + b),
+                _ => None,
+            },
+        }
+    }
+
+    #[must_use]
+    pub const fn accepts(&self, count: usize) -> bool {
+        count >= self.min
+            && match self.max {
+                Some(max) => count <= max,
+                None => true,
+            }
+    }
+}
+
+impl Display for Arity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "{max}"),
+            Some(max) => write!(f, "{} to {max}", self.min),
+            None => write!(f, "at least {}", self.min),
+        }
+    }
+}
+
+/// A type that a [`Function`] can take as a parameter, converted from the arguments it is
+/// called with.
+///
+/// Besides any single [Value] conversion, [Option] parameters accept an argument that may be
+/// omitted and [Vec] parameters collect all remaining arguments. Both must come after any
+/// required parameters.
+pub trait Parameter: Sized {
+    const ARITY: Arity;
+
+    /// Take this parameter from the front of the remaining [args].
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if a required argument is missing or of the wrong type.
+    fn take(args: &mut IntoIter<Value>) -> Result<Self, CallError>;
+}
+
+fn convert<T>(value: Value) -> Result<T, CallError>
+where
+    T: TryFrom<Value, Error = ValueError>,
+{
+    T::try_from(value.clone()).map_err(|_| CallError::InvalidArguments(type_name::<T>(), value))
+}
+
+impl<T> Parameter for T
+where
+    T: TryFrom<Value, Error = ValueError>,
+{
+    const ARITY: Arity = Arity::exactly(1);
+
+    fn take(args: &mut IntoIter<Value>) -> Result<Self, CallError> {
+        let value = args.next().ok_or(CallError::InvalidArguments(
+            type_name::<T>(),
+            Value::NullValue,
+        ))?;
+
+        convert(value)
+    }
+}
+
+impl Parameter for Value {
+    const ARITY: Arity = Arity::exactly(1);
+
+    fn take(args: &mut IntoIter<Value>) -> Result<Self, CallError> {
+        args.next().ok_or(CallError::InvalidArguments(
+            type_name::<Self>(),
+            Value::NullValue,
+        ))
+    }
+}
+
+impl<T> Parameter for Option<T>
+where
+    T: TryFrom<Value, Error = ValueError>,
+{
+    const ARITY: Arity = Arity {
+        min: 0,
+        max: Some(1),
+    };
+
+    /// A missing argument and a null argument both give [None], rather than the value null
+    /// converts to.
+    fn take(args: &mut IntoIter<Value>) -> Result<Self, CallError> {
+        match args.next() {
+            None | Some(Value::NullValue) => Ok(None),
+            Some(value) => convert(value).map(Some),
+        }
+    }
+}
+
+impl<T> Parameter for Vec<T>
+where
+    T: TryFrom<Value, Error = ValueError>,
+{
+    const ARITY: Arity = Arity { min: 0, max: None };
+
+    fn take(args: &mut IntoIter<Value>) -> Result<Self, CallError> {
+        args.map(convert).collect()
+    }
+}
+
+impl Parameter for Vec<Value> {
+    const ARITY: Arity = Arity { min: 0, max: None };
+
+    fn take(args: &mut IntoIter<Value>) -> Result<Self, CallError> {
+        Ok(args.collect())
+    }
+}
+
+/// A type that can be returned from a [`Function`].
+///
+/// Functions can return anything that converts into a [Value], or a [Result] of such a type
+/// to report failure as a [`CallError::User`].
+pub trait FunctionReturn {
+    /// # Errors
+    ///
+    /// Returns `Err` if the function failed.
+    fn into_value(self) -> Result<Value, CallError>;
+
+    /// The name of the type of value returned when the function succeeds.
+    fn value_type_name() -> &'static str;
+}
+
+impl<T> FunctionReturn for T
+where
+    T: Into<Value>,
+{
+    fn into_value(self) -> Result<Value, CallError> {
+        Ok(self.into())
+    }
+
+    fn value_type_name() -> &'static str {
+        type_name::<T>()
+    }
+}
+
+impl<T, E> FunctionReturn for Result<T, E>
+where
+    T: Into<Value>,
+    E: Into<Box<dyn Error + Send + Sync>>,
+{
+    fn into_value(self) -> Result<Value, CallError> {
+        self.map(Into::into)
+            .map_err(|error| CallError::User(error.into()))
+    }
+
+    fn value_type_name() -> &'static str {
+        type_name::<T>()
+    }
 }
 
 /// A function that can be registered with and called by scripts running in the Yarn runtime.
 pub trait Function<Ty> {
-    type Return: Into<Value>;
+    type Return: FunctionReturn;
+
+    const ARITY: Arity;
 
     fn call(&self, context: CallContext, args: Vec<Value>) -> Result<Value, CallError>;
+
+    fn signature() -> Signature;
 }
 
 pub trait UntypedFunction {
     fn call(&self, context: CallContext, args: Vec<Value>) -> Result<Value, CallError>;
+
+    fn signature(&self) -> &Signature;
 }
 
 pub struct FunctionHandle<S, F>
@@ -102,6 +479,7 @@ where
     F: Function<S>,
 {
     function: F,
+    signature: Signature,
     marker: PhantomData<S>,
 }
 
@@ -112,17 +490,40 @@ where
     fn call(&self, context: CallContext, args: Vec<Value>) -> Result<Value, CallError> {
         self.function.call(context, args)
     }
+
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
 }
 
 pub struct CallContext<'r> {
     pub node: &'r Node,
     pub story: &'r Story,
     pub variables: &'r mut dyn VariableStore,
+    pub rng: &'r mut Rng,
+
+    /// Data supplied by the caller of [`StoryRunner::step_with_user_data`], such as game state
+    /// that functions need to query or modify.
+    ///
+    /// [`StoryRunner::step_with_user_data`]: crate::runner::StoryRunner::step_with_user_data
+    pub user_data: &'r mut dyn Any,
 }
 
-macro_rules! param_count {
-    () => {0};
-    ($head: tt $($tail:tt)*) => { 1 + param_count!($($tail)*) }; 
+impl<'r> CallContext<'r> {
+    /// Get the user data given to the runner if it is of type [T].
+    pub fn user_data_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.user_data.downcast_mut()
+    }
+
+    /// Get the user data given to the runner, which is expected to be of type [T].
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the user data is of another type.
+    pub fn try_user_data_mut<T: Any>(&mut self) -> Result<&mut T, CallError> {
+        self.user_data_mut()
+            .ok_or(CallError::UserDataType(type_name::<T>()))
+    }
 }
 
 // https://github.com/yarn-slinger/yarn-slinger/blob/6b74f8d3b9d5caace05240ba1bf737dff2035b1f/crates/core/src/yarn_fn/function_wrapping.rs#L21
@@ -132,30 +533,42 @@ macro_rules! impl_function {
         impl<F, R, $($param,)*> Function<fn(CallContext, $($param,)*) -> R> for F
         where
             F: Fn(CallContext, $($param,)*) -> R,
-            $($param: TryFrom<Value, Error = ValueError>,)*
-            R: Into<Value>,
+            $($param: Parameter,)*
+            R: FunctionReturn,
         {
             type Return = R;
 
-            #[allow(non_snake_case)]
+            const ARITY: Arity = Arity::exactly(0)$(.then($param::ARITY))*;
+
+            #[allow(non_snake_case, unused_mut, unused_variables)]
             fn call(&self, context: CallContext, args: Vec<Value>) -> Result<Value, CallError> {
-                    let [$($param,)*] = &args[..] else {
-                       return Err(CallError::InvalidArgumentCount(param_count!($($param)*), args.len()));
-                    };
-
-                    let input = (
-                        $($param
-                            .clone()
-                            .try_into()
-                            .or_else(|_| Err(CallError::InvalidArguments(type_name::<$param>(), $param.clone())))?,
-                        )*
-                    );
-                    let ($($param,)*) = input;
-                    Ok(self(context, $($param,)*).into())
+                if !Self::ARITY.accepts(args.len()) {
+                    return Err(CallError::InvalidArgumentCount(Self::ARITY, args.len()));
+                }
+
+                let mut args = args.into_iter();
+                $(let $param = $param::take(&mut args)?;)*
+
+                self(context, $($param,)*).into_value()
+            }
+
+            fn signature() -> Signature {
+                Signature {
+                    parameters: vec![$(type_name::<$param>(),)*],
+                    returns: R::value_type_name(),
+                    arity: Self::ARITY,
+                }
             }
         }
     };
 }
 
+impl_function!();
 impl_function!(P1);
 impl_function!(P1, P2);
+impl_function!(P1, P2, P3);
+impl_function!(P1, P2, P3, P4);
+impl_function!(P1, P2, P3, P4, P5);
+impl_function!(P1, P2, P3, P4, P5, P6);
+impl_function!(P1, P2, P3, P4, P5, P6, P7);
+impl_function!(P1, P2, P3, P4, P5, P6, P7, P8);
@@ -1,19 +1,8 @@
 use super::CallContext;
-use crate::model::Value;
-
-pub fn visit_count_var_name(node_name: &str) -> String {
-    format!("$Yarn.Internal.Visiting.{node_name}")
-}
 
 /// Get the number of times the node named by [name] has been visited.
 pub fn visited_count(context: CallContext, name: String) -> f32 {
-    let var_name = visit_count_var_name(&name);
-
-    if let Some(Value::FloatValue(visit_count)) = context.variables.get(&var_name) {
-        *visit_count
-    } else {
-        0.0
-    }
+    context.story.visit_count(context.variables, &name)
 }
 
 /// Check if the node identified by [name] has been visited before.
@@ -21,3 +10,109 @@ pub fn visited(context: CallContext, name: String) -> bool {
     let visit_count = visited_count(context, name);
     visit_count > 0.0
 }
+
+/// Get the remainder of dividing [a] by [b] as integers, matching the integer modulus used by
+/// Yarn Spinner. Both operands are truncated towards zero first, so `-7.5 % 3` is `-1`.
+///
+/// # Errors
+///
+/// Returns `Err` if [b] truncates to zero.
+pub fn modulo(_context: CallContext, a: f32, b: f32) -> Result<f32, &'static str> {
+    (a as i32)
+        .checked_rem(b as i32)
+        .map(|rem| rem as f32)
+        .ok_or("attempted to calculate the modulus with a divisor of zero")
+}
+
+/// Round [n] to the nearest integer, rounding midpoints to the nearest even integer.
+pub fn round(_context: CallContext, n: f32) -> f32 {
+    round_half_even(f64::from(n)) as f32
+}
+
+/// Round [n] to the given number of decimal [places], rounding midpoints to the nearest even
+/// value.
+pub fn round_places(_context: CallContext, n: f32, places: f32) -> f32 {
+    let scale = 10_f64.powi(places.clamp(0.0, 15.0) as i32);
+    (round_half_even(f64::from(n) * scale) / scale) as f32
+}
+
+/// Get the fractional part of [n], which has the same sign as [n].
+pub fn decimal(_context: CallContext, n: f32) -> f32 {
+    n - n.trunc()
+}
+
+/// Increment [n] to the next integer, or round it up if it is not an integer.
+pub fn inc(_context: CallContext, n: f32) -> f32 {
+    if n.fract() == 0.0 {
+        n + 1.0
+    } else {
+        n.ceil()
+    }
+}
+
+/// Decrement [n] to the previous integer, or round it down if it is not an integer.
+pub fn dec(_context: CallContext, n: f32) -> f32 {
+    if n.fract() == 0.0 {
+        n - 1.0
+    } else {
+        n.floor()
+    }
+}
+
+/// Convert [value] to a number. Booleans convert to `1` or `0`, and text that is not a number
+/// converts to `NaN`.
+pub fn number(_context: CallContext, value: String) -> f32 {
+    match value.trim() {
+        "true" => 1.0,
+        "false" | "null" => 0.0,
+        value => value.parse().unwrap_or(f32::NAN),
+    }
+}
+
+/// Convert [value] to a boolean. Numbers are `true` if they are non-zero, and text is `true` if
+/// it reads `true` in any case.
+pub fn bool(_context: CallContext, value: String) -> bool {
+    let value = value.trim();
+    value
+        .parse::<f32>()
+        .map_or_else(|_| value.eq_ignore_ascii_case("true"), |n| n != 0.0)
+}
+
+/// Format [n] independently of the current locale, with `.` as the decimal separator and no
+/// grouping of digits.
+pub fn format_invariant(_context: CallContext, n: f32) -> String {
+    format!("{n}")
+}
+
+fn round_half_even(n: f64) -> f64 {
+    let rounded = n.round();
+
+    if (rounded - n).abs() == 0.5 && rounded % 2.0 != 0.0 {
+        rounded - n.signum()
+    } else {
+        rounded
+    }
+}
+
+/// Generate a random number between `0` (inclusive) and `1` (exclusive).
+pub fn random(context: CallContext) -> f32 {
+    context.rng.next_f32()
+}
+
+/// Generate a random integer between [min] and [max], inclusive of both.
+pub fn random_range(context: CallContext, min: f32, max: f32) -> f32 {
+    context.rng.range(min as i64, max as i64) as f32
+}
+
+/// Roll a die with the given number of [sides], giving an integer between `1` and [sides].
+///
+/// # Errors
+///
+/// Returns `Err` if the die has fewer than one side.
+pub fn dice(context: CallContext, sides: f32) -> Result<f32, &'static str> {
+    if sides.is_nan() || sides < 1.0 {
+        return Err("attempted to roll a die with fewer than one side");
+    }
+
+    Ok(random_range(context, 1.0, sides))
+}
@@ -1,7 +1,12 @@
 #![warn(clippy::all, clippy::missing_errors_doc, clippy::missing_safety_doc)]
 #![deny(clippy::panic)]
 
+pub mod asm;
+pub mod character;
+pub mod command;
 pub mod function;
+pub mod line;
+pub mod markup;
 pub mod model;
 pub mod runner;
 pub mod state;
@@ -9,7 +14,7 @@ pub mod story;
 pub mod variables;
 
 pub mod prelude {
-    pub use super::{runner::*, story::*};
+    pub use super::{runner::*, state::*, story::*};
 }
 
 #[cfg(test)]
@@ -17,7 +22,18 @@ mod tests {
     use std::collections::HashMap;
 
     use super::prelude::*;
-    use crate::function::Library;
+    use crate::asm::{assemble, disassemble, AssembleError};
+    use crate::character::{Character, CharacterRegistry};
+    use crate::command::{split_command_text, CommandRegistry};
+    use crate::function::{Arity, CallContext, CallError, Library, LinkError};
+    use crate::line::{LineError, LineProvider, Localization, ResolvedLine};
+    use crate::markup::{
+        cardinal, ordinal, parse as parse_markup, parse_localized, MarkupError, MarkupValue,
+        PluralCase,
+    };
+    use crate::model::{
+        Instruction, Node, NodeBuilder, OpCode, Operand, Program, ProgramBuilder, Value,
+    };
 
     type TestResult = Result<(), Box<dyn std::error::Error>>;
 
@@ -45,11 +61,1966 @@ mod tests {
             (previous, event) = runner.step(&story, previous, &mut vars)?;
 
             match event {
-                StoryEvent::Complete => break,
+                StoryEvent::DialogueComplete => break,
                 _ => eprintln!("{event:#?}"),
             }
         }
 
         Ok(())
     }
+
+    #[test]
+    pub fn restores_saved_checkpoint() -> TestResult {
+        let story = Builder::default()
+            .add_file(test_case!("sample-stories/sally.yarnc"))
+            .build()?;
+
+        let runner = StoryRunner::new(Library::default());
+
+        let mut vars = HashMap::new();
+        let mut checkpoint = story
+            .checkpoint_at("Sally")
+            .expect("unable to find start node");
+
+        (checkpoint, _) = runner.step(&story, checkpoint, &mut vars)?;
+
+        let saved = SavedCheckpoint::from_bytes(&checkpoint.save().to_bytes())?;
+        let mut restored = story.restore(&saved)?;
+        let mut restored_vars = vars.clone();
+
+        loop {
+            let expected: StoryEvent;
+            let actual: StoryEvent;
+            (checkpoint, expected) = runner.step(&story, checkpoint, &mut vars)?;
+            (restored, actual) = runner.step(&story, restored, &mut restored_vars)?;
+
+            assert_eq!(expected, actual);
+
+            if expected == StoryEvent::DialogueComplete {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn tracks_node_visits() -> TestResult {
+        let story = Builder::default()
+            .add_file(test_case!("sample-stories/sally.yarnc"))
+            .build()?;
+
+        let runner = StoryRunner::new(Library::default());
+
+        let mut vars = HashMap::new();
+        for _ in 0..2 {
+            let mut checkpoint = story
+                .checkpoint_at("Sally.Exit")
+                .expect("unable to find exit node");
+
+            loop {
+                let event: StoryEvent;
+                (checkpoint, event) = runner.step(&story, checkpoint, &mut vars)?;
+
+                if event == StoryEvent::DialogueComplete {
+                    break;
+                }
+            }
+        }
+
+        assert_eq!(2.0, story.visit_count(&vars, "Sally.Exit"));
+        assert_eq!(0.0, story.visit_count(&vars, "Sally"));
+        assert_eq!(
+            vec![
+                ("Declarations", 0.0),
+                ("Sally", 0.0),
+                ("Sally.Exit", 2.0),
+                ("Sally.Sorry", 0.0),
+                ("Sally.Watch", 0.0)
+            ],
+            story.visit_counts(&vars).collect::<Vec<_>>()
+        );
+
+        story.reset_visit_counts(&mut vars);
+        assert!(story.visit_counts(&vars).all(|(_, count)| count == 0.0));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn emits_node_lifecycle_events() -> TestResult {
+        let story = Builder::default()
+            .add_file(test_case!("sample-stories/sally.yarnc"))
+            .build()?;
+
+        let runner = StoryRunner::new(Library::default());
+
+        let mut vars = HashMap::new();
+        let mut checkpoint = story
+            .checkpoint_at("Sally.Exit")
+            .expect("unable to find exit node");
+
+        let mut events = vec![];
+        loop {
+            let event: StoryEvent;
+            (checkpoint, event) = runner.step(&story, checkpoint, &mut vars)?;
+
+            if event == StoryEvent::DialogueComplete {
+                break;
+            }
+
+            events.push(event);
+        }
+
+        let name = "Sally.Exit".to_string();
+        assert_eq!(
+            Some(&StoryEvent::NodeStart { name: name.clone() }),
+            events.first()
+        );
+        assert_eq!(Some(&StoryEvent::NodeComplete { name }), events.last());
+
+        Ok(())
+    }
+
+    fn instruction(opcode: OpCode, operands: Vec<Value>) -> Instruction {
+        Instruction {
+            opcode: opcode as i32,
+            operands: operands.into_iter().map(Operand::from).collect(),
+        }
+    }
+
+    fn options_program() -> Program {
+        let string = |s: &str| Value::StringValue(s.to_string());
+        let node = Node {
+            name: "Start".to_string(),
+            instructions: vec![
+                instruction(OpCode::AddOption, vec![string("line:a"), string("A")]),
+                instruction(OpCode::PushBool, vec![Value::BoolValue(false)]),
+                instruction(
+                    OpCode::AddOption,
+                    vec![
+                        string("line:b"),
+                        string("B"),
+                        Value::FloatValue(0.0),
+                        Value::BoolValue(true),
+                    ],
+                ),
+                instruction(OpCode::ShowOptions, vec![]),
+                instruction(OpCode::Jump, vec![]),
+                instruction(OpCode::RunLine, vec![string("line:chose-a")]),
+                instruction(OpCode::Stop, vec![]),
+                instruction(OpCode::RunLine, vec![string("line:chose-b")]),
+                instruction(OpCode::Stop, vec![]),
+            ],
+            labels: HashMap::from([("A".to_string(), 5), ("B".to_string(), 7)]),
+            ..Node::default()
+        };
+
+        Program {
+            nodes: HashMap::from([(node.name.clone(), node)]),
+            ..Program::default()
+        }
+    }
+
+    #[test]
+    pub fn runs_dialogue_with_options() -> TestResult {
+        let story = Builder::default().add_program(options_program()).build()?;
+
+        let runner = StoryRunner::new(Library::default());
+        let mut dialogue = Dialogue::new(&runner, &story, HashMap::new());
+        dialogue.start("Start")?;
+
+        let options = loop {
+            if let DialogueEvent::Options(options) = dialogue.advance()? {
+                break options;
+            }
+        };
+
+        let ids: Vec<_> = options
+            .iter()
+            .map(|o| (o.index, o.line_id.as_str(), o.enabled))
+            .collect();
+        assert_eq!(vec![(0, "line:a", true), (1, "line:b", false)], ids);
+        assert_eq!(options, dialogue.options());
+        assert!(matches!(
+            dialogue.advance(),
+            Err(DialogueError::AwaitingSelection)
+        ));
+
+        let saved = dialogue
+            .checkpoint()
+            .expect("dialogue should be running")
+            .save();
+        let mut restored = Dialogue::new(&runner, &story, HashMap::new());
+        restored.resume(story.restore(&saved)?);
+        assert_eq!(options, restored.options());
+        assert!(matches!(
+            dialogue.select_option(2),
+            Err(DialogueError::Option(OptionError::InvalidIndex(2)))
+        ));
+        assert!(matches!(
+            dialogue.select_option(1),
+            Err(DialogueError::Option(OptionError::Disabled(_)))
+        ));
+
+        dialogue.select_option(0)?;
+
+        let mut lines = vec![];
+        loop {
+            match dialogue.advance()? {
+                DialogueEvent::Line { line_id, .. } => lines.push(line_id),
+                DialogueEvent::DialogueComplete => break,
+                _ => {}
+            }
+        }
+
+        assert_eq!(vec!["line:chose-a".to_string()], lines);
+        assert!(!dialogue.is_running());
+        assert!(dialogue.options().is_empty());
+
+        let node = Node {
+            name: "Start".to_string(),
+            instructions: vec![
+                instruction(
+                    OpCode::PushVariable,
+                    vec![Value::StringValue("$name".into())],
+                ),
+                instruction(
+                    OpCode::RunLine,
+                    vec![
+                        Value::StringValue("line:hello".into()),
+                        Value::FloatValue(1.0),
+                    ],
+                ),
+                instruction(OpCode::Stop, vec![]),
+            ],
+            ..Node::default()
+        };
+        let program = Program {
+            nodes: HashMap::from([(node.name.clone(), node)]),
+            ..Program::default()
+        };
+
+        let story = Builder::default().add_program(program).build()?;
+        let mut dialogue = Dialogue::new(&runner, &story, HashMap::new());
+        dialogue.start("Start")?;
+        dialogue.advance()?;
+
+        assert!(matches!(dialogue.advance(), Err(DialogueError::Runner(_))));
+        assert!(dialogue.is_running());
+
+        dialogue
+            .variables_mut()
+            .insert("$name".to_string(), Value::StringValue("Sally".into()));
+        assert_eq!(
+            DialogueEvent::Line {
+                line_id: "line:hello".to_string(),
+                substitutions: vec!["Sally".to_string()],
+            },
+            dialogue.advance()?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn rejects_unknown_options() -> TestResult {
+        let story = Builder::default().add_program(options_program()).build()?;
+        let runner = StoryRunner::new(Library::default());
+
+        let mut vars = HashMap::new();
+        let mut checkpoint = story
+            .checkpoint_at("Start")
+            .expect("unable to find start node");
+
+        assert_eq!(
+            Err(OptionError::NotAwaitingSelection),
+            checkpoint.select_option("A")
+        );
+
+        loop {
+            let event: StoryEvent;
+            (checkpoint, event) = runner.step(&story, checkpoint, &mut vars)?;
+
+            if event == StoryEvent::ShowOptions {
+                break;
+            }
+        }
+
+        assert!(runner.step(&story, checkpoint.clone(), &mut vars).is_err());
+        assert_eq!(
+            Err(OptionError::UnknownOption("Typo".to_string())),
+            checkpoint.select_option("Typo")
+        );
+        assert_eq!(
+            Err(OptionError::Disabled("B".to_string())),
+            checkpoint.select_option("B")
+        );
+
+        checkpoint = story.restore(&checkpoint.save())?;
+        checkpoint.select_option("A")?;
+
+        let (_, event) = runner.step(&story, checkpoint, &mut vars)?;
+        assert_eq!(
+            StoryEvent::ShowLine {
+                key: "line:chose-a".to_string(),
+                substitutions: vec![]
+            },
+            event
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn supports_null_values() -> TestResult {
+        let node = Node {
+            name: "Start".to_string(),
+            instructions: vec![
+                instruction(OpCode::PushNull, vec![]),
+                instruction(OpCode::StoreVariable, vec![Value::StringValue("$x".into())]),
+                instruction(OpCode::Pop, vec![]),
+                instruction(OpCode::PushVariable, vec![Value::StringValue("$x".into())]),
+                instruction(OpCode::PushNull, vec![]),
+                instruction(OpCode::PushFloat, vec![Value::FloatValue(1.0)]),
+                instruction(OpCode::PushFloat, vec![Value::FloatValue(2.0)]),
+                instruction(
+                    OpCode::CallFunc,
+                    vec![Value::StringValue("Number.Add".into())],
+                ),
+                instruction(
+                    OpCode::RunLine,
+                    vec![Value::StringValue("line:x".into()), Value::FloatValue(2.0)],
+                ),
+                instruction(OpCode::Stop, vec![]),
+            ],
+            ..Node::default()
+        };
+
+        let program = Program {
+            nodes: HashMap::from([(node.name.clone(), node)]),
+            ..Program::default()
+        };
+
+        let story = Builder::default().add_program(program).build()?;
+        let runner = StoryRunner::new(Library::default());
+
+        let mut vars = HashMap::new();
+        let mut checkpoint = story
+            .checkpoint_at("Start")
+            .expect("unable to find start node");
+        let mut event = StoryEvent::DialogueComplete;
+        for _ in 0..2 {
+            (checkpoint, event) = runner.step(&story, checkpoint, &mut vars)?;
+        }
+
+        assert_eq!(Some(&Value::NullValue), vars.get("$x"));
+        assert_eq!(
+            StoryEvent::ShowLine {
+                key: "line:x".to_string(),
+                substitutions: vec!["null".to_string(), "1".to_string()]
+            },
+            event
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn yields_when_budget_exhausted() -> TestResult {
+        let node = Node {
+            name: "Start".to_string(),
+            instructions: vec![instruction(
+                OpCode::JumpTo,
+                vec![Value::StringValue("L0".into())],
+            )],
+            labels: HashMap::from([("L0".to_string(), 0)]),
+            ..Node::default()
+        };
+
+        let program = Program {
+            nodes: HashMap::from([(node.name.clone(), node)]),
+            ..Program::default()
+        };
+
+        let story = Builder::default().add_program(program).build()?;
+        let runner = StoryRunner::new(Library::default());
+
+        let mut vars = HashMap::new();
+        let mut checkpoint = story
+            .checkpoint_at("Start")
+            .expect("unable to find start node");
+        let mut outcome: StepOutcome;
+
+        (checkpoint, outcome) = runner.step_with_budget(&story, checkpoint, &mut vars, 100)?;
+        assert!(matches!(
+            outcome,
+            StepOutcome::Event(StoryEvent::NodeStart { .. })
+        ));
+
+        for _ in 0..3 {
+            (checkpoint, outcome) = runner.step_with_budget(&story, checkpoint, &mut vars, 100)?;
+            assert_eq!(StepOutcome::Yielded, outcome);
+        }
+
+        let runner = runner.with_budget_exhaustion(BudgetExhaustion::Error);
+        assert!(runner
+            .step_with_budget(&story, checkpoint, &mut vars, 100)
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn rejects_undecodable_instructions() {
+        let node = Node {
+            name: "Start".to_string(),
+            instructions: vec![
+                instruction(OpCode::PushFloat, vec![Value::FloatValue(1.0)]),
+                Instruction {
+                    opcode: 99,
+                    operands: vec![],
+                },
+            ],
+            ..Node::default()
+        };
+
+        let program = Program {
+            nodes: HashMap::from([(node.name.clone(), node)]),
+            ..Program::default()
+        };
+
+        assert!(matches!(
+            Builder::default().add_program(program).build(),
+            Err(BuilderError::Instruction(name, 1, _)) if name == "Start"
+        ));
+    }
+
+    #[test]
+    pub fn splits_command_text() {
+        assert_eq!(
+            vec!["walk", "Sally", "to the \"door\"", "a\\b", "2"],
+            split_command_text(r#"  walk Sally "to the \"door\"" "a\\b"   2 "#)
+        );
+        assert_eq!(
+            vec!["say", "unterminated"],
+            split_command_text(r#"say "unterminated"#)
+        );
+        assert!(split_command_text("   ").is_empty());
+        assert_eq!(
+            vec!["say", "ab cd", "e"],
+            split_command_text(r#"say a"b c"d e"#)
+        );
+    }
+
+    #[test]
+    pub fn dispatches_registered_commands() -> TestResult {
+        let string = |s: &str| Value::StringValue(s.to_string());
+        let node = Node {
+            name: "Start".to_string(),
+            instructions: vec![
+                instruction(OpCode::PushFloat, vec![Value::FloatValue(3.0)]),
+                instruction(
+                    OpCode::RunCommand,
+                    vec![
+                        string("set_mood \"very happy\" {0}"),
+                        Value::FloatValue(1.0),
+                    ],
+                ),
+                instruction(
+                    OpCode::RunCommand,
+                    vec![string("note inf nan -infinity -2.5 .5 x\"1\"")],
+                ),
+                instruction(OpCode::RunCommand, vec![string("wave Sally")]),
+                instruction(OpCode::Stop, vec![]),
+            ],
+            ..Node::default()
+        };
+
+        let program = Program {
+            nodes: HashMap::from([(node.name.clone(), node)]),
+            ..Program::default()
+        };
+
+        let mut commands = CommandRegistry::new();
+        commands.register("set_mood", |cx: CallContext, mood: String, level: f32| {
+            cx.variables.set("$mood", Value::StringValue(mood));
+            cx.variables.set("$level", Value::FloatValue(level));
+        });
+
+        commands.register("note", |cx: CallContext, args: Vec<Value>| {
+            cx.variables
+                .set("$notes", Value::StringValue(format!("{args:?}")));
+        });
+
+        let story = Builder::default().add_program(program).build()?;
+        let runner = StoryRunner::new(Library::default()).with_commands(commands);
+
+        let mut vars = HashMap::new();
+        let mut checkpoint = story
+            .checkpoint_at("Start")
+            .expect("unable to find start node");
+        let mut event: StoryEvent;
+
+        (checkpoint, event) = runner.step(&story, checkpoint, &mut vars)?;
+        assert!(matches!(event, StoryEvent::NodeStart { .. }));
+
+        (_, event) = runner.step(&story, checkpoint, &mut vars)?;
+        assert_eq!(StoryEvent::Command("wave Sally".to_string()), event);
+        assert_eq!(Some(&string("very happy")), vars.get("$mood"));
+        assert_eq!(Some(&Value::FloatValue(3.0)), vars.get("$level"));
+
+        let notes = [
+            string("inf"),
+            string("nan"),
+            string("-infinity"),
+            Value::FloatValue(-2.5),
+            Value::FloatValue(0.5),
+            string("x1"),
+        ];
+        assert_eq!(Some(&string(&format!("{notes:?}"))), vars.get("$notes"));
+
+        Ok(())
+    }
+
+    fn call(name: &str, args: Vec<Value>) -> Result<Value, Box<dyn std::error::Error>> {
+        Ok(call_in(&Library::builtins(), name, args)?)
+    }
+
+    fn call_in(library: &Library, name: &str, args: Vec<Value>) -> Result<Value, CallError> {
+        let story = Builder::default()
+            .add_program(options_program())
+            .build()
+            .expect("unable to build story");
+        let node = story.node("Start").expect("unable to find start node");
+        let cx = CallContext {
+            node,
+            story: &story,
+            variables: &mut HashMap::new(),
+            rng: &mut Rng::default(),
+            user_data: &mut (),
+        };
+
+        library.call(name, cx, args)
+    }
+
+    #[test]
+    pub fn evaluates_operators() -> TestResult {
+        let float = Value::FloatValue;
+        let string = |s: &str| Value::StringValue(s.to_string());
+
+        assert_eq!(
+            float(-1.0),
+            call("Number.Minus", vec![float(1.0), float(2.0)])?
+        );
+        assert_eq!(
+            float(6.0),
+            call("Number.Multiply", vec![float(2.0), float(3.0)])?
+        );
+        assert_eq!(
+            float(f32::INFINITY),
+            call("Number.Divide", vec![float(1.0), float(0.0)])?
+        );
+        assert_eq!(
+            float(-1.0),
+            call("Number.Modulo", vec![float(-7.5), float(3.0)])?
+        );
+        assert!(matches!(
+            call_in(
+                &Library::builtins(),
+                "Number.Modulo",
+                vec![float(1.0), float(0.5)]
+            ),
+            Err(CallError::User(_))
+        ));
+        assert!(!Library::builtins().contains("Number.Subtract"));
+        assert_eq!(float(-2.0), call("Number.UnaryMinus", vec![float(2.0)])?);
+        assert_eq!(
+            Value::BoolValue(true),
+            call("Number.LessThanOrEqualTo", vec![float(2.0), float(2.0)])?
+        );
+        assert_eq!(
+            Value::BoolValue(true),
+            call(
+                "Bool.Xor",
+                vec![Value::BoolValue(true), Value::BoolValue(false)]
+            )?
+        );
+        assert_eq!(
+            string("level 2.5"),
+            call("String.Add", vec![string("level "), float(2.5)])?
+        );
+        assert_eq!(
+            Value::BoolValue(false),
+            call("String.NotEqualTo", vec![string("a"), string("a")])?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn evaluates_standard_functions() -> TestResult {
+        let float = Value::FloatValue;
+        let string = |s: &str| Value::StringValue(s.to_string());
+        let cases = [
+            ("round", vec![float(2.5)], float(2.0)),
+            ("round", vec![float(3.5)], float(4.0)),
+            ("round", vec![float(-2.5)], float(-2.0)),
+            ("round", vec![float(2.6)], float(3.0)),
+            ("round_places", vec![float(1.125), float(2.0)], float(1.12)),
+            (
+                "round_places",
+                vec![float(1.23456), float(3.0)],
+                float(1.235),
+            ),
+            ("truncate", vec![float(-3.7)], float(-3.0)),
+            ("int", vec![float(3.7)], float(3.0)),
+            ("decimal", vec![float(3.75)], float(0.75)),
+            ("decimal", vec![float(-3.75)], float(-0.75)),
+            ("inc", vec![float(2.0)], float(3.0)),
+            ("inc", vec![float(2.5)], float(3.0)),
+            ("dec", vec![float(2.0)], float(1.0)),
+            ("dec", vec![float(2.5)], float(2.0)),
+            ("string", vec![float(1.5)], string("1.5")),
+            ("string", vec![Value::BoolValue(true)], string("true")),
+            ("number", vec![string("42.5")], float(42.5)),
+            ("number", vec![Value::BoolValue(true)], float(1.0)),
+            ("bool", vec![float(0.0)], Value::BoolValue(false)),
+            ("bool", vec![string("True")], Value::BoolValue(true)),
+            ("format_invariant", vec![float(1234.5)], string("1234.5")),
+            ("format_invariant", vec![float(-0.25)], string("-0.25")),
+        ];
+
+        for (name, args, expected) in cases {
+            assert_eq!(expected, call(name, args.clone())?, "{name}({args:?})");
+        }
+
+        assert!(matches!(
+            call("number", vec![string("many")])?,
+            Value::FloatValue(value) if value.is_nan()
+        ));
+
+        Ok(())
+    }
+
+    fn roll<'a>(
+        runner: &StoryRunner,
+        story: &'a Story,
+        checkpoint: StoryCheckpoint<'a>,
+    ) -> Result<(StoryCheckpoint<'a>, Vec<String>), StoryRunnerError> {
+        let (checkpoint, event) = runner.step(story, checkpoint, &mut HashMap::new())?;
+        let StoryEvent::ShowLine { substitutions, .. } = event else {
+            unreachable!("expected a line, found {event:?}");
+        };
+
+        Ok((checkpoint, substitutions))
+    }
+
+    #[test]
+    pub fn replays_random_rolls() -> TestResult {
+        let float = Value::FloatValue;
+        let string = |s: &str| Value::StringValue(s.to_string());
+        let node = Node {
+            name: "Start".to_string(),
+            instructions: vec![
+                instruction(OpCode::PushFloat, vec![float(6.0)]),
+                instruction(OpCode::PushFloat, vec![float(1.0)]),
+                instruction(OpCode::CallFunc, vec![string("dice")]),
+                instruction(OpCode::PushFloat, vec![float(10.0)]),
+                instruction(OpCode::PushFloat, vec![float(20.0)]),
+                instruction(OpCode::PushFloat, vec![float(2.0)]),
+                instruction(OpCode::CallFunc, vec![string("random_range")]),
+                instruction(OpCode::RunLine, vec![string("line:roll"), float(2.0)]),
+                instruction(OpCode::JumpTo, vec![string("L0")]),
+            ],
+            labels: HashMap::from([("L0".to_string(), 0)]),
+            ..Node::default()
+        };
+
+        let program = Program {
+            nodes: HashMap::from([(node.name.clone(), node)]),
+            ..Program::default()
+        };
+
+        let story = Builder::default().add_program(program).build()?;
+        let runner = StoryRunner::new(Library::default());
+
+        let start = story
+            .checkpoint_at("Start")
+            .expect("unable to find start node")
+            .with_seed(42);
+        let (mut checkpoint, _) = runner.step(&story, start, &mut HashMap::new())?;
+        let mut rolls;
+
+        (checkpoint, _) = roll(&runner, &story, checkpoint)?;
+        let saved = checkpoint.save();
+
+        let mut expected = vec![];
+        for _ in 0..10 {
+            (checkpoint, rolls) = roll(&runner, &story, checkpoint)?;
+
+            let dice: f32 = rolls[0].parse()?;
+            let range: f32 = rolls[1].parse()?;
+            assert!((1.0..=6.0).contains(&dice) && dice.fract() == 0.0);
+            assert!((10.0..=20.0).contains(&range) && range.fract() == 0.0);
+
+            expected.push(rolls);
+        }
+
+        let mut restored = story.restore(&saved)?;
+        for expected in expected {
+            (restored, rolls) = roll(&runner, &story, restored)?;
+            assert_eq!(expected, rolls);
+        }
+
+        let unseeded = || story.checkpoint_at("Start").map(|start| start.save());
+        assert_ne!(unseeded(), unseeded());
+
+        for sides in [0.0, -6.0, f32::NAN] {
+            assert!(matches!(
+                call_in(&Library::builtins(), "dice", vec![float(sides)]),
+                Err(CallError::User(_))
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn calls_functions_of_any_arity() -> TestResult {
+        let float = Value::FloatValue;
+        let string = |s: &str| Value::StringValue(s.to_string());
+
+        let mut library = Library::new();
+        library.register("time_of_day", |_ctx: CallContext| "evening".to_string());
+        library.register(
+            "clamp",
+            |_ctx: CallContext, value: f32, min: f32, max: f32| value.clamp(min, max),
+        );
+        library.register(
+            "greet",
+            |_ctx: CallContext, name: String, title: Option<String>| match title {
+                Some(title) => format!("{title} {name}"),
+                None => name,
+            },
+        );
+        library.register("sum", |_ctx: CallContext, first: f32, rest: Vec<f32>| {
+            first + rest.iter().sum::<f32>()
+        });
+        library.register("count", |_ctx: CallContext, args: Vec<Value>| {
+            args.len() as f32
+        });
+        library.register(
+            "eight",
+            |_ctx: CallContext, a: f32, b: f32, c: f32, d: f32, e: f32, f: f32, g: f32, h: f32| {
+                a + b + c + d + e + f + g + h
+            },
+        );
+
+        assert_eq!(string("evening"), call_in(&library, "time_of_day", vec![])?);
+        assert_eq!(
+            float(2.0),
+            call_in(&library, "clamp", vec![float(5.0), float(0.0), float(2.0)])?
+        );
+        assert_eq!(
+            string("Sally"),
+            call_in(&library, "greet", vec![string("Sally")])?
+        );
+        assert_eq!(
+            string("Sally"),
+            call_in(&library, "greet", vec![string("Sally"), Value::NullValue])?
+        );
+        assert_eq!(
+            string("Dr Sally"),
+            call_in(&library, "greet", vec![string("Sally"), string("Dr")])?
+        );
+        assert_eq!(float(1.0), call_in(&library, "sum", vec![float(1.0)])?);
+        assert_eq!(
+            float(6.0),
+            call_in(&library, "sum", vec![float(1.0), float(2.0), float(3.0)])?
+        );
+        assert_eq!(
+            float(2.0),
+            call_in(&library, "count", vec![string("a"), Value::BoolValue(true)])?
+        );
+        assert_eq!(float(8.0), call_in(&library, "eight", vec![float(1.0); 8])?);
+
+        assert!(matches!(
+            call_in(&library, "time_of_day", vec![float(1.0)]),
+            Err(CallError::InvalidArgumentCount(
+                Arity {
+                    min: 0,
+                    max: Some(0)
+                },
+                1
+            ))
+        ));
+        assert!(matches!(
+            call_in(&library, "greet", vec![]),
+            Err(CallError::InvalidArgumentCount(
+                Arity {
+                    min: 1,
+                    max: Some(2)
+                },
+                0
+            ))
+        ));
+        assert!(matches!(
+            call_in(&library, "sum", vec![]),
+            Err(CallError::InvalidArgumentCount(
+                Arity { min: 1, max: None },
+                0
+            ))
+        ));
+        assert!(matches!(
+            call_in(&library, "sum", vec![float(1.0), Value::BoolValue(true)]),
+            Err(CallError::InvalidArguments(_, Value::BoolValue(true)))
+        ));
+        assert_eq!(
+            "invalid argument count, expected 1 to 2, found 3",
+            call_in(&library, "greet", vec![string("a"); 3])
+                .expect_err("too many arguments")
+                .to_string()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn passes_user_data_to_functions() -> TestResult {
+        #[derive(Default)]
+        struct Inventory {
+            items: Vec<String>,
+        }
+
+        let string = |s: &str| Value::StringValue(s.to_string());
+        let node = Node {
+            name: "Start".to_string(),
+            instructions: vec![
+                instruction(OpCode::RunCommand, vec![string("give key")]),
+                instruction(OpCode::PushString, vec![string("key")]),
+                instruction(OpCode::PushFloat, vec![Value::FloatValue(1.0)]),
+                instruction(OpCode::CallFunc, vec![string("has_item")]),
+                instruction(OpCode::PushString, vec![string("")]),
+                instruction(OpCode::PushFloat, vec![Value::FloatValue(1.0)]),
+                instruction(OpCode::CallFunc, vec![string("has_item")]),
+                instruction(OpCode::Stop, vec![]),
+            ],
+            ..Node::default()
+        };
+
+        let program = Program {
+            nodes: HashMap::from([(node.name.clone(), node)]),
+            ..Program::default()
+        };
+
+        let mut library = Library::new();
+        library.register("has_item", |mut cx: CallContext, item: String| {
+            if item.is_empty() {
+                return Err("item name is empty");
+            }
+
+            let inventory = cx.user_data_mut::<Inventory>().ok_or("no inventory")?;
+            Ok(inventory.items.contains(&item))
+        });
+
+        let mut commands = CommandRegistry::new();
+        commands.register("give", |mut cx: CallContext, item: String| {
+            cx.try_user_data_mut::<Inventory>()?.items.push(item);
+            Ok::<_, CallError>(())
+        });
+
+        let story = Builder::default().add_program(program).build()?;
+        let runner = StoryRunner::new(library).with_commands(commands);
+
+        let mut inventory = Inventory::default();
+        let checkpoint = story
+            .checkpoint_at("Start")
+            .expect("unable to find start node");
+        let (checkpoint, _) =
+            runner.step_with_user_data(&story, checkpoint, &mut HashMap::new(), &mut inventory)?;
+        let error = runner
+            .step_with_user_data(&story, checkpoint, &mut HashMap::new(), &mut inventory)
+            .err()
+            .expect("function should fail");
+
+        assert_eq!(vec!["key".to_string()], inventory.items);
+        assert!(matches!(
+            std::error::Error::source(&error),
+            Some(source) if source.to_string() == "function failed: item name is empty"
+        ));
+
+        let mut inventory = Inventory::default();
+        let mut checkpoint = story
+            .checkpoint_at("Start")
+            .expect("unable to find start node");
+        let mut outcomes = vec![];
+        for budget in [1, 1, 2] {
+            let outcome;
+            (checkpoint, outcome) = runner.step_with_budget_and_user_data(
+                &story,
+                checkpoint,
+                &mut HashMap::new(),
+                budget,
+                &mut inventory,
+            )?;
+            outcomes.push(outcome);
+        }
+
+        assert!(matches!(
+            outcomes.as_slice(),
+            [
+                StepOutcome::Event(StoryEvent::NodeStart { .. }),
+                StepOutcome::Yielded,
+                StepOutcome::Yielded
+            ]
+        ));
+        assert_eq!(vec!["key".to_string()], inventory.items);
+
+        let checkpoint = story
+            .checkpoint_at("Start")
+            .expect("unable to find start node");
+        let (checkpoint, _) = runner.step(&story, checkpoint, &mut HashMap::new())?;
+        let error = runner
+            .step(&story, checkpoint, &mut HashMap::new())
+            .err()
+            .expect("command should fail without an inventory");
+        assert!(
+            std::iter::successors(std::error::Error::source(&error), |e| e.source()).any(|e| {
+                matches!(
+                    e.downcast_ref::<CallError>(),
+                    Some(CallError::UserDataType(_))
+                )
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn checks_story_against_library() -> TestResult {
+        let sally = Builder::default()
+            .add_file(test_case!("sample-stories/sally.yarnc"))
+            .build()?;
+        let library = Library::builtins();
+
+        assert_eq!(Vec::<LinkError>::new(), library.check(&sally));
+
+        let string = |s: &str| Value::StringValue(s.to_string());
+        let node = Node {
+            name: "Start".to_string(),
+            instructions: vec![
+                instruction(OpCode::PushFloat, vec![Value::FloatValue(0.0)]),
+                instruction(OpCode::CallFunc, vec![string("time_of_day")]),
+                instruction(OpCode::PushFloat, vec![Value::FloatValue(1.0)]),
+                instruction(OpCode::PushFloat, vec![Value::FloatValue(1.0)]),
+                instruction(OpCode::CallFunc, vec![string("round_places")]),
+                instruction(OpCode::Stop, vec![]),
+            ],
+            ..Node::default()
+        };
+
+        let program = Program {
+            nodes: HashMap::from([(node.name.clone(), node)]),
+            ..Program::default()
+        };
+
+        let story = Builder::default().add_program(program).build()?;
+        let signature = library
+            .signature("round_places")
+            .expect("round_places is a builtin");
+
+        assert_eq!("(f32, f32) -> f32", signature.to_string());
+        assert_eq!(
+            vec![
+                LinkError::UnknownFunction {
+                    node: "Start".to_string(),
+                    pc: 1,
+                    name: "time_of_day".to_string(),
+                },
+                LinkError::InvalidArgumentCount {
+                    node: "Start".to_string(),
+                    pc: 4,
+                    name: "round_places".to_string(),
+                    expected: Arity::exactly(2),
+                    found: 1,
+                },
+            ],
+            library.check(&story)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn resolves_constant_targets_ahead_of_time() -> TestResult {
+        let float = Value::FloatValue;
+        let string = |s: &str| Value::StringValue(s.to_string());
+        let node = |name: &str, instructions: Vec<Instruction>, labels: &[(&str, i32)]| Node {
+            name: name.to_string(),
+            instructions,
+            labels: labels
+                .iter()
+                .map(|(label, offset)| (label.to_string(), *offset))
+                .collect(),
+            ..Node::default()
+        };
+        let program = |nodes: Vec<Node>| Program {
+            nodes: nodes
+                .into_iter()
+                .map(|node| (node.name.clone(), node))
+                .collect(),
+            ..Program::default()
+        };
+
+        let story = Builder::default()
+            .add_program(program(vec![
+                node(
+                    "Start",
+                    vec![
+                        instruction(OpCode::PushString, vec![string("L3")]),
+                        instruction(OpCode::Jump, vec![]),
+                        instruction(OpCode::RunLine, vec![string("line:skipped")]),
+                        instruction(OpCode::PushString, vec![string("Next")]),
+                        instruction(OpCode::RunNode, vec![]),
+                    ],
+                    &[("L3", 3)],
+                ),
+                node(
+                    "Next",
+                    vec![
+                        instruction(OpCode::PushFloat, vec![float(2.5)]),
+                        instruction(OpCode::PushFloat, vec![float(1.0)]),
+                        instruction(OpCode::CallFunc, vec![string("floor")]),
+                        instruction(OpCode::RunLine, vec![string("line:next"), float(1.0)]),
+                        instruction(OpCode::Stop, vec![]),
+                    ],
+                    &[],
+                ),
+            ]))
+            .build()?;
+
+        assert_eq!(Vec::<Diagnostic>::new(), story.validate());
+
+        let runner = StoryRunner::new(Library::default());
+        let run = |story: &Story, start: &str| -> Result<Vec<StoryEvent>, StoryRunnerError> {
+            let mut checkpoint = story.checkpoint_at(start).expect("node should exist");
+            let mut events = vec![];
+
+            loop {
+                let event;
+                (checkpoint, event) = runner.step(story, checkpoint, &mut HashMap::new())?;
+
+                if event == StoryEvent::DialogueComplete {
+                    return Ok(events);
+                }
+
+                events.push(event);
+            }
+        };
+
+        let name = |name: &str| name.to_string();
+        assert_eq!(
+            vec![
+                StoryEvent::NodeStart {
+                    name: name("Start")
+                },
+                StoryEvent::NodeComplete {
+                    name: name("Start")
+                },
+                StoryEvent::NodeStart { name: name("Next") },
+                StoryEvent::ShowLine {
+                    key: name("line:next"),
+                    substitutions: vec![name("2")],
+                },
+                StoryEvent::NodeComplete { name: name("Next") },
+            ],
+            run(&story, "Start")?
+        );
+
+        // The same runner resolves functions afresh for a story that interns other names.
+        let other = Builder::default()
+            .add_program(program(vec![node(
+                "Start",
+                vec![
+                    instruction(OpCode::PushFloat, vec![float(2.5)]),
+                    instruction(OpCode::PushFloat, vec![float(1.0)]),
+                    instruction(OpCode::CallFunc, vec![string("ceil")]),
+                    instruction(OpCode::RunLine, vec![string("line:next"), float(1.0)]),
+                    instruction(OpCode::Stop, vec![]),
+                ],
+                &[],
+            )]))
+            .build()?;
+
+        assert!(run(&other, "Start")?.contains(&StoryEvent::ShowLine {
+            key: name("line:next"),
+            substitutions: vec![name("3")],
+        }));
+        assert!(run(&story, "Next")?.contains(&StoryEvent::ShowLine {
+            key: name("line:next"),
+            substitutions: vec![name("2")],
+        }));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn validates_story() -> TestResult {
+        let sally = Builder::default()
+            .add_file(test_case!("sample-stories/sally.yarnc"))
+            .build()?;
+
+        assert_eq!(Vec::<Diagnostic>::new(), sally.validate());
+
+        let string = |s: &str| Value::StringValue(s.to_string());
+        let node = Node {
+            name: "Start".to_string(),
+            instructions: vec![
+                instruction(OpCode::PushVariable, vec![string("$undeclared")]),
+                instruction(OpCode::JumpIfFalse, vec![string("Nowhere")]),
+                instruction(OpCode::AddOption, vec![string("line:a"), string("Missing")]),
+                instruction(OpCode::JumpIfFalse, vec![string("L5")]),
+                // Paths merge at L5 with one or two values on the stack, which is not reported.
+                instruction(OpCode::PushFloat, vec![Value::FloatValue(1.0)]),
+                instruction(OpCode::Pop, vec![]),
+                instruction(OpCode::Pop, vec![]),
+                instruction(OpCode::PushString, vec![string("Elsewhere")]),
+                instruction(OpCode::RunNode, vec![]),
+            ],
+            labels: HashMap::from([("L5".to_string(), 5)]),
+            ..Node::default()
+        };
+
+        let program = Program {
+            nodes: HashMap::from([(node.name.clone(), node)]),
+            ..Program::default()
+        };
+
+        let story = Builder::default().add_program(program).build()?;
+        let kinds: Vec<_> = story
+            .validate()
+            .into_iter()
+            .map(|diagnostic| (diagnostic.pc, diagnostic.kind))
+            .collect();
+
+        assert_eq!(
+            vec![
+                (
+                    0,
+                    DiagnosticKind::UndefinedVariable("$undeclared".to_string())
+                ),
+                (1, DiagnosticKind::UnknownLabel("Nowhere".to_string())),
+                (
+                    2,
+                    DiagnosticKind::UnknownOptionTarget("Missing".to_string())
+                ),
+                (
+                    6,
+                    DiagnosticKind::StackUnderflow {
+                        needed: 1,
+                        available: 0
+                    }
+                ),
+                (8, DiagnosticKind::UnknownNode("Elsewhere".to_string())),
+            ],
+            kinds
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn exports_node_graph() -> TestResult {
+        let string = |s: &str| Value::StringValue(s.to_string());
+        let node = |name: &str, instructions: Vec<Instruction>| Node {
+            name: name.to_string(),
+            instructions,
+            ..Node::default()
+        };
+
+        let nodes = [
+            node(
+                "Start",
+                vec![
+                    instruction(OpCode::AddOption, vec![string("line:b"), string("B")]),
+                    instruction(OpCode::ShowOptions, vec![]),
+                    instruction(OpCode::PushString, vec![string("A")]),
+                    instruction(OpCode::RunNode, vec![]),
+                ],
+            ),
+            node("A", vec![instruction(OpCode::Stop, vec![])]),
+            node(
+                "B",
+                vec![
+                    instruction(OpCode::PushString, vec![string("Start")]),
+                    instruction(OpCode::RunNode, vec![]),
+                ],
+            ),
+            node("C", vec![instruction(OpCode::Stop, vec![])]),
+        ];
+
+        let program = Program {
+            nodes: nodes
+                .into_iter()
+                .map(|node| (node.name.clone(), node))
+                .collect(),
+            ..Program::default()
+        };
+
+        let story = Builder::default().add_program(program).build()?;
+        let graph = story.graph();
+
+        assert_eq!(
+            vec![
+                ("B", "Start", EdgeKind::Run),
+                ("Start", "B", EdgeKind::Option),
+                ("Start", "A", EdgeKind::Run),
+            ],
+            graph
+                .edges()
+                .iter()
+                .map(|edge| (edge.from.as_str(), edge.to.as_str(), edge.kind))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(vec!["C"], graph.unreachable_from(&["Start"]));
+        assert_eq!(vec!["A", "C"], graph.dead_ends());
+        assert_eq!(
+            "digraph story {\n    \"A\";\n    \"B\";\n    \"C\";\n    \"Start\";\n    \
+             \"B\" -> \"Start\";\n    \"Start\" -> \"B\" [style=dashed];\n    \
+             \"Start\" -> \"A\";\n}\n",
+            graph.to_dot()
+        );
+        assert_eq!(
+            r#"{"nodes":["A","B","C","Start"],"edges":[{"from":"B","to":"Start","kind":"run","pc":1},{"from":"Start","to":"B","kind":"option","pc":0},{"from":"Start","to":"A","kind":"run","pc":3}]}"#,
+            graph.to_json()
+        );
+
+        let odd = "say \"hi\"\\\n\t\u{1}";
+        let program = Program {
+            nodes: HashMap::from([(odd.to_string(), node(odd, vec![]))]),
+            ..Program::default()
+        };
+        let graph = Builder::default().add_program(program).build()?.graph();
+        assert_eq!(
+            "digraph story {\n    \"say \\\"hi\\\"\\\\\\n\t\u{1}\";\n}\n",
+            graph.to_dot()
+        );
+        assert_eq!(
+            r#"{"nodes":["say \"hi\"\\\n\t\u0001"],"edges":[]}"#,
+            graph.to_json()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn round_trips_disassembly() -> TestResult {
+        use prost::Message;
+
+        let data = std::fs::read(test_case!("sample-stories/sally.yarnc"))?;
+        let sally = Program::decode(&data[..])?;
+        assert_eq!(sally, assemble(&disassemble(&sally))?);
+
+        let mut node = options_program().nodes.remove("Start").expect("start node");
+        node.instructions.push(Instruction {
+            opcode: 99,
+            operands: vec![
+                Operand { value: None },
+                Operand::from(Value::StringValue(
+                    "quote \" slash \\ tab\t: ; \u{1}".into(),
+                )),
+                Operand::from(Value::FloatValue(-0.1)),
+            ],
+        });
+        node.labels.insert("before".to_string(), -1);
+        node.labels
+            .insert("end".to_string(), node.instructions.len() as i32);
+        node.labels.insert("beyond".to_string(), 100);
+        node.tags = vec!["a tag".to_string(), String::new()];
+        node.source_text_string_id = "line:source".to_string();
+        node.headers = vec![crate::model::Header {
+            key: "title".to_string(),
+            value: "Start".to_string(),
+        }];
+
+        let program = Program {
+            name: "edge cases".to_string(),
+            nodes: HashMap::from([("Start".to_string(), node)]),
+            initial_values: HashMap::from([(
+                "$x".to_string(),
+                Operand::from(Value::BoolValue(true)),
+            )]),
+        };
+
+        assert_eq!(program, assemble(&disassemble(&program))?);
+
+        let nan = assemble(".node \"Start\"\n    PUSH_FLOAT NaN\n")?;
+        assert!(disassemble(&nan).contains("PUSH_FLOAT NaN"));
+        assert!(matches!(
+            nan.nodes["Start"].instructions[0].operands[0].value,
+            Some(crate::model::operand::Value::FloatValue(value)) if value.is_nan()
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn assembles_handwritten_programs() -> TestResult {
+        let program = assemble(
+            r#"
+            .node "Start"
+                PUSH_BOOL true      ; no offsets are needed
+                JUMP_IF_FALSE "skip"
+                RUN_LINE "line:yes"
+            "skip":
+                STOP
+            "#,
+        )?;
+
+        let story = Builder::default().add_program(program).build()?;
+        let runner = StoryRunner::new(Library::default());
+
+        let mut vars = HashMap::new();
+        let mut checkpoint = story
+            .checkpoint_at("Start")
+            .expect("unable to find start node");
+        let mut event = StoryEvent::DialogueComplete;
+        for _ in 0..2 {
+            (checkpoint, event) = runner.step(&story, checkpoint, &mut vars)?;
+        }
+
+        assert_eq!(
+            StoryEvent::ShowLine {
+                key: "line:yes".to_string(),
+                substitutions: vec![]
+            },
+            event
+        );
+        assert_eq!(
+            Err(AssembleError::OffsetMismatch(3, 2, 1)),
+            assemble(".node \"A\"\n    0000 STOP\n    0002 STOP")
+        );
+        assert_eq!(
+            Err(AssembleError::UnknownOpcode(2, "LEAP".to_string())),
+            assemble(".node \"A\"\nLEAP")
+        );
+        assert_eq!(
+            Err(AssembleError::UnterminatedString(1)),
+            assemble(".node \"A")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn builds_programs() -> TestResult {
+        let program = ProgramBuilder::new("built")
+            .initial_value("$gold", 10.0)
+            .node(
+                NodeBuilder::new("Start")
+                    .tag("intro")
+                    .jump_to("ask")
+                    .label("rich")
+                    .line("line:rich", 0)
+                    .stop()
+                    .label("ask")
+                    .push_variable("$gold")
+                    .push_float(5.0)
+                    .call("Number.GreaterThan", 2)
+                    .conditional_option("line:buy", "rich", 0)
+                    .option("line:leave", "Shop", 0)
+                    .show_options()
+                    .jump(),
+            )
+            .node(NodeBuilder::new("Shop").line("line:shop", 0))
+            .build();
+
+        let start = &program.nodes["Start"];
+        assert_eq!(
+            Ok(1),
+            start.resolve_label("rich").map_err(|e| e.to_string())
+        );
+        assert_eq!(Ok(3), start.resolve_label("ask").map_err(|e| e.to_string()));
+        assert_eq!(vec!["intro".to_string()], start.tags);
+
+        let story = Builder::default().add_program(program).build()?;
+        assert!(story.validate().is_empty());
+
+        let runner = StoryRunner::new(Library::default());
+        let mut dialogue = Dialogue::new(&runner, &story, HashMap::new());
+        dialogue.start("Start")?;
+
+        let options = loop {
+            if let DialogueEvent::Options(options) = dialogue.advance()? {
+                break options;
+            }
+        };
+
+        assert_eq!(
+            vec![("line:buy", true), ("line:leave", true)],
+            options
+                .iter()
+                .map(|o| (o.line_id.as_str(), o.enabled))
+                .collect::<Vec<_>>()
+        );
+
+        dialogue.select_option(0)?;
+        assert_eq!(
+            DialogueEvent::Line {
+                line_id: "line:rich".to_string(),
+                substitutions: vec![]
+            },
+            dialogue.advance()?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn resolves_line_text() -> TestResult {
+        let lines = LineProvider::from_files(
+            test_case!("sample-stories/sally-Lines.csv"),
+            Some(test_case!("sample-stories/sally-Metadata.csv")),
+        )?;
+
+        assert_eq!(16, lines.len());
+        assert_eq!(Some("Player: Hey, Sally."), lines.text("line:794945"));
+
+        let info = lines.line("line:2dc39b").expect("line should be loaded");
+        assert_eq!(("Sally", 13), (info.node.as_str(), info.line_number));
+
+        let mut lines = LineProvider::new();
+        lines.load_lines(concat!(
+            "id,text,file,node,lineNumber\r\n",
+            "line:gold,\"You have {0} gold, \"\"{1}\"\".\",input.yarn,Shop,3\r\n",
+            "line:multi,\"Two\nlines {2}\",input.yarn,Shop,4\r\n",
+        ))?;
+        lines.load_metadata(concat!(
+            "id,node,lineNumber,tags\n",
+            "line:gold,Shop,3,lastline shop\n",
+            "line:multi,Shop,4,one,#two\n",
+        ))?;
+
+        let substitutions = ["10".to_string(), "friend".to_string()];
+        assert_eq!(
+            Some("You have 10 gold, \"friend\".".to_string()),
+            lines.resolve("line:gold", &substitutions)
+        );
+        assert_eq!(
+            Some("Two\nlines {2}".to_string()),
+            lines.resolve("line:multi", &substitutions)
+        );
+        assert_eq!(None, lines.resolve("line:missing", &substitutions));
+
+        assert_eq!(["lastline", "shop"], lines.tags("line:gold"));
+        assert_eq!(["one", "two"], lines.tags("line:multi"));
+        assert!(lines.tags("line:missing").is_empty());
+
+        lines.load_metadata("id,node,lineNumber,tags\nline:orphan,Shop,9,later\n")?;
+        assert_eq!(None, lines.line("line:orphan"));
+        assert_eq!(None, lines.resolve("line:orphan", &[]));
+
+        lines.load_lines("id,text\nline:orphan,Later\n")?;
+        assert_eq!(["later"], lines.tags("line:orphan"));
+
+        let mut german = LineProvider::new();
+        german.load_metadata("id,node,lineNumber,tags\nline:gold,Shop,3,lastline\n")?;
+        let localization = Localization::new("en", lines.clone()).with_locale("de", german);
+        assert_eq!(
+            Some("You have 10 gold, \"friend\".".to_string()),
+            localization.resolve("de", "line:gold", &substitutions)
+        );
+
+        assert!(matches!(
+            LineProvider::new().load_lines("id,file\nline:a,input.yarn\n"),
+            Err(LineError::MissingColumn("text"))
+        ));
+        assert!(matches!(
+            LineProvider::new().load_lines("id,text\nline:a,\"open\n"),
+            Err(LineError::UnterminatedQuote(2))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn localizes_lines() -> TestResult {
+        fn table(rows: &str) -> Result<LineProvider, LineError> {
+            let mut lines = LineProvider::new();
+            lines.load_lines(&format!("id,text,file,node,lineNumber\n{rows}"))?;
+            Ok(lines)
+        }
+
+        let localization = Localization::new(
+            "en",
+            table("line:hello,Hello,,,\nline:gold,{0} gold,,,\nline:bye,Goodbye,,,\n")?,
+        )
+        .with_locale("pt", table("line:hello,Olá,,,\nline:gold,{0} ouro,,,\n")?)
+        .with_locale("pt-BR", table("line:hello,Oi,,,\n")?)
+        .with_locale("de", table("line:hello,Hallo,,,\n")?)
+        .with_fallbacks("de-AT", ["de"]);
+
+        assert_eq!(
+            vec!["pt-BR", "pt", "en"],
+            localization.fallback_chain("pt-BR")
+        );
+        assert_eq!(vec!["de", "en"], localization.fallback_chain("de-AT"));
+        assert_eq!(
+            vec!["line:bye", "line:gold"],
+            localization.missing_keys("pt-BR")
+        );
+        assert_eq!(vec!["line:bye"], localization.missing_keys("pt"));
+
+        let program = ProgramBuilder::new("localized").node(
+            NodeBuilder::new("Start")
+                .line("line:hello", 0)
+                .push_float(3.0)
+                .call("string", 1)
+                .line("line:gold", 1)
+                .line("line:bye", 0),
+        );
+        let story = Builder::default()
+            .add_program(program)
+            .localization(localization)
+            .build()?;
+
+        let runner = StoryRunner::new(Library::default());
+        let mut dialogue = Dialogue::new(&runner, &story, HashMap::new());
+        assert_eq!(Some("en"), dialogue.locale());
+        assert!(matches!(
+            dialogue.set_locale("fr"),
+            Err(DialogueError::UnknownLocale(locale)) if locale == "fr"
+        ));
+
+        dialogue.set_locale("de-AT")?;
+        assert_eq!(Some("de-AT"), dialogue.locale());
+        assert_eq!(
+            Some("Hallo".to_string()),
+            dialogue.line_text("line:hello", &[])
+        );
+        assert_eq!(
+            Some("Goodbye".to_string()),
+            dialogue.line_text("line:bye", &[])
+        );
+        dialogue.set_locale("de-CH")?;
+        assert_eq!(
+            Some("Hallo".to_string()),
+            dialogue.line_text("line:hello", &[])
+        );
+
+        dialogue.set_locale("pt-BR")?;
+        dialogue.start("Start")?;
+
+        let mut text = vec![];
+        loop {
+            match dialogue.advance()? {
+                DialogueEvent::Line {
+                    line_id,
+                    substitutions,
+                } => {
+                    text.push(dialogue.line_text(&line_id, &substitutions));
+                    dialogue.set_locale("en")?;
+                }
+                DialogueEvent::DialogueComplete => break,
+                _ => {}
+            }
+        }
+
+        assert_eq!(
+            vec![
+                Some("Oi".to_string()),
+                Some("3 gold".to_string()),
+                Some("Goodbye".to_string())
+            ],
+            text
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn parses_markup() -> TestResult {
+        let result = parse_markup(
+            r#"Sally: [b]Bold [wave size=2 speed=0.5 name="a \"b\""]wavy[/wave][/b] [pause=500/] \[x\]"#,
+        )?;
+
+        assert_eq!("Sally: Bold wavy [x]", result.text);
+        assert_eq!(Some("Sally"), result.character_name());
+
+        let spans: Vec<_> = result
+            .attributes
+            .iter()
+            .map(|a| (a.name.as_str(), result.text_for_attribute(a)))
+            .collect();
+        assert_eq!(
+            vec![
+                ("character", "Sally: "),
+                ("b", "Bold wavy"),
+                ("wave", "wavy"),
+                ("pause", "")
+            ],
+            spans
+        );
+
+        let wave = result.attribute("wave").expect("wave should be parsed");
+        assert_eq!(Some(&MarkupValue::Integer(2)), wave.property("size"));
+        assert_eq!(Some(&MarkupValue::Float(0.5)), wave.property("speed"));
+        assert_eq!(
+            Some(&MarkupValue::String("a \"b\"".to_string())),
+            wave.property("name")
+        );
+        let pause = result.attribute("pause").expect("pause should be parsed");
+        assert_eq!(Some(&MarkupValue::Integer(500)), pause.property("pause"));
+        assert_eq!(17, pause.position);
+
+        let character = result
+            .attribute("character")
+            .expect("character is implicit");
+        let without_name = result.delete_range(character);
+        assert_eq!("Bold wavy [x]", without_name.text);
+        assert_eq!(
+            Some("wavy"),
+            without_name
+                .attribute("wave")
+                .map(|a| without_name.text_for_attribute(a))
+        );
+
+        let result = parse_markup(
+            "[character name=\"Sal\"]Sally: [/character]a [nomarkup][b]ö[/nomarkup] [/]",
+        )?;
+        assert_eq!("Sally: a [b]ö ", result.text);
+        assert_eq!(Some("Sal"), result.character_name());
+        let no_markup = result.attribute("nomarkup").expect("nomarkup is parsed");
+        assert_eq!("[b]ö", result.text_for_attribute(no_markup));
+
+        let result = parse_markup("[a/] one [b trimwhitespace=false/] two [c][d]x[/]")?;
+        assert_eq!("one  two x", result.text);
+        assert_eq!(
+            Some(&MarkupValue::Bool(false)),
+            result
+                .attribute("b")
+                .and_then(|b| b.property("trimwhitespace"))
+        );
+        assert!(result.attribute("c").is_some() && result.attribute("d").is_some());
+
+        assert_eq!(
+            Err(MarkupError::UnexpectedCloseMarker("b".to_string(), 4)),
+            parse_markup("text[/b]")
+        );
+        assert_eq!(
+            Err(MarkupError::UnclosedMarker("b".to_string(), 0)),
+            parse_markup("[b]text")
+        );
+        assert_eq!(
+            Err(MarkupError::UnterminatedMarker(5)),
+            parse_markup("text [b")
+        );
+        assert_eq!(
+            Err(MarkupError::UnterminatedString(8)),
+            parse_markup("[a name=\"x]")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn evaluates_replacement_markers() -> TestResult {
+        assert_eq!(
+            vec![
+                PluralCase::One,
+                PluralCase::Few,
+                PluralCase::Many,
+                PluralCase::Many,
+                PluralCase::Other
+            ],
+            [1.0, 22.0, 25.0, 12.0, 1.5]
+                .map(|n| cardinal("pl", n))
+                .to_vec()
+        );
+        assert_eq!(
+            vec![
+                PluralCase::One,
+                PluralCase::Few,
+                PluralCase::Many,
+                PluralCase::One
+            ],
+            [21.0, 3.0, 11.0, 101.0]
+                .map(|n| cardinal("ru-RU", n))
+                .to_vec()
+        );
+        assert_eq!(PluralCase::One, cardinal("fr", 0.0));
+        assert_eq!(
+            vec![
+                PluralCase::One,
+                PluralCase::Many,
+                PluralCase::Many,
+                PluralCase::Other
+            ],
+            [("es", 1.0), ("es", 1e6), ("it", 2e6), ("pt-PT", 1e6 + 1.0)]
+                .map(|(locale, n)| cardinal(locale, n))
+                .to_vec()
+        );
+        assert_eq!(PluralCase::Other, cardinal("en", 0.0));
+        assert_eq!(PluralCase::Other, cardinal("ja", 1.0));
+        assert_eq!(
+            vec![
+                PluralCase::One,
+                PluralCase::Two,
+                PluralCase::Few,
+                PluralCase::Other
+            ],
+            [21.0, 2.0, 103.0, 11.0]
+                .map(|n| ordinal("en-GB", n))
+                .to_vec()
+        );
+
+        let apples = r#"You have {0} [plural value={0} one="apple" few="jabłka" many="jabłek" other="apples"/]."#;
+        let resolve = |locale, count: &str| {
+            parse_localized(&apples.replace("{0}", count), locale).map(|result| result.text)
+        };
+        assert_eq!(Ok("You have 1 apple.".to_string()), resolve("en", "1"));
+        assert_eq!(Ok("You have 3 apples.".to_string()), resolve("en", "3"));
+        assert_eq!(Ok("You have 3 jabłka.".to_string()), resolve("pl", "3"));
+        assert_eq!(Ok("You have 5 jabłek.".to_string()), resolve("pl", "5"));
+        assert_eq!(Ok("You have 1.0 apples.".to_string()), resolve("en", "1.0"));
+        assert_eq!(
+            Ok("You have 1.50 apples.".to_string()),
+            resolve("en", "1.50")
+        );
+        assert_eq!(Ok("You have 1.0 apples.".to_string()), resolve("pl", "1.0"));
+        assert_eq!(
+            Ok("You have 1000000 jabłek.".to_string()),
+            resolve("es", "1000000")
+        );
+        assert_eq!(
+            Err(MarkupError::InvalidValue(
+                "plural".to_string(),
+                MarkupValue::String("many".to_string()),
+                14
+            )),
+            resolve("en", "many")
+        );
+
+        let result = parse_markup(
+            r#"[select value=f m="He" f="She"/] came [ordinal value=22 one="%st" two="%nd" few="%rd" other="%th"/]."#,
+        )?;
+        assert_eq!("She came 22nd.", result.text);
+        assert!(result.attributes.is_empty());
+
+        assert_eq!(
+            Err(MarkupError::MissingProperty(
+                "select".to_string(),
+                "x".to_string(),
+                0
+            )),
+            parse_markup(r#"[select value=x m="He"/]"#)
+        );
+
+        let program = ProgramBuilder::new("plurals").node(
+            NodeBuilder::new("Start")
+                .push_float(4.0)
+                .call("string", 1)
+                .line("line:apples", 1),
+        );
+        let mut lines = LineProvider::new();
+        lines.load_lines(&format!(
+            "id,text,file,node,lineNumber\nline:apples,\"{}\",,,\n",
+            apples.replace('"', "\"\"")
+        ))?;
+        let story = Builder::default()
+            .add_program(program)
+            .localization(Localization::new("pl", lines))
+            .build()?;
+
+        let runner = StoryRunner::new(Library::default());
+        let mut dialogue = Dialogue::new(&runner, &story, HashMap::new());
+        dialogue.start("Start")?;
+
+        let line = loop {
+            if let DialogueEvent::Line {
+                line_id,
+                substitutions,
+            } = dialogue.advance()?
+            {
+                break dialogue.parse_line(&line_id, &substitutions)?;
+            }
+        };
+        assert_eq!("You have 4 jabłka.", line.text);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn extracts_speakers() -> TestResult {
+        let mut lines =
+            LineProvider::from_files(test_case!("sample-stories/sally-Lines.csv"), None::<&str>)?;
+        lines.load_lines(concat!(
+            "id,text,file,node,lineNumber\n",
+            "line:note,Note\\: [b]no speaker[/b],,,\n",
+            "line:named,\"[character name=\"\"Sally\"\"]Sal: [/character]Psst.\",,,\n",
+        ))?;
+        lines.load_metadata("id,node,lineNumber,tags\nline:794945,Sally,12,greeting\n")?;
+
+        let story = Builder::default()
+            .add_file(test_case!("sample-stories/sally.yarnc"))
+            .localization(Localization::new("en", lines))
+            .build()?;
+        let runner = StoryRunner::new(Library::default());
+        let dialogue = Dialogue::new(&runner, &story, HashMap::new());
+
+        let line = dialogue.resolve_line("line:794945", &[])?;
+        assert_eq!(
+            ResolvedLine {
+                id: "line:794945".to_string(),
+                text: "Hey, Sally.".to_string(),
+                speaker: Some("Player".to_string()),
+                attributes: vec![],
+                tags: vec!["greeting".to_string()],
+            },
+            line
+        );
+
+        let note = dialogue.resolve_line("line:note", &[])?;
+        assert_eq!(
+            (None, "Note: no speaker"),
+            (note.speaker.as_deref(), note.text.as_str())
+        );
+        assert_eq!(6, note.attributes[0].position);
+
+        let named = dialogue.resolve_line("line:named", &[])?;
+        assert_eq!(
+            (Some("Sally"), "Psst."),
+            (named.speaker.as_deref(), named.text.as_str())
+        );
+
+        let mut characters = CharacterRegistry::new();
+        characters
+            .register("Sally", Character::new().with_portrait("sally.png"))
+            .register(
+                "Player",
+                Character::new()
+                    .with_display_name("You")
+                    .with_voice("player"),
+            );
+
+        let portraits: Vec<_> = [&line, &note, &named]
+            .into_iter()
+            .map(|line| {
+                characters
+                    .speaker(line)
+                    .and_then(|character| character.portrait.as_deref())
+            })
+            .collect();
+        assert_eq!(vec![None, None, Some("sally.png")], portraits);
+        assert_eq!(
+            Some("You"),
+            characters
+                .speaker(&line)
+                .and_then(|character| character.display_name.as_deref())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn queries_node_tags_and_headers() -> TestResult {
+        let program = ProgramBuilder::new("tagged")
+            .node(
+                NodeBuilder::new("Guard")
+                    .tag("bark")
+                    .header("speaker", "Guard")
+                    .line("line:halt", 0),
+            )
+            .node(
+                NodeBuilder::new("Quest")
+                    .tag("quest")
+                    .header("quest", "rescue")
+                    .header("quest", "ignored")
+                    .line("line:quest", 0),
+            )
+            .node(
+                NodeBuilder::new("Beggar")
+                    .tag("bark")
+                    .tag("quest")
+                    .line("line:alms", 0),
+            );
+        let story = Builder::default()
+            .add_program(program)
+            .add_file(test_case!("sample-stories/sally.yarnc"))
+            .build()?;
+
+        assert_eq!(
+            vec![
+                "Beggar",
+                "Declarations",
+                "Guard",
+                "Quest",
+                "Sally",
+                "Sally.Exit",
+                "Sally.Sorry",
+                "Sally.Watch"
+            ],
+            story
+                .nodes()
+                .map(|node| node.name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["Beggar", "Guard"],
+            story
+                .nodes_with_tag("bark")
+                .map(|node| node.name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some(&["bark".to_string(), "quest".to_string()][..]),
+            story.node_tags("Beggar")
+        );
+        assert_eq!(None, story.node_tags("Missing"));
+        assert_eq!(Some("Guard"), story.node_header("Guard", "speaker"));
+        assert_eq!(Some("rescue"), story.node_header("Quest", "quest"));
+        assert_eq!(None, story.node_header("Guard", "quest"));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn stays_complete_after_stopping() -> TestResult {
+        let program = ProgramBuilder::new("stop").node(
+            NodeBuilder::new("Start")
+                .line("line:a", 0)
+                .stop()
+                .line("line:after_stop", 0),
+        );
+        let story = Builder::default().add_program(program).build()?;
+        let runner = StoryRunner::new(Library::default());
+
+        let mut vars = HashMap::new();
+        let mut checkpoint = story.checkpoint_at("Start").expect("node should exist");
+        let mut events = vec![];
+        loop {
+            let event;
+            (checkpoint, event) = runner.step(&story, checkpoint, &mut vars)?;
+            let complete = event == StoryEvent::DialogueComplete;
+            events.push(event);
+            if complete {
+                break;
+            }
+        }
+
+        for _ in 0..2 {
+            let event;
+            (checkpoint, event) = runner.step(&story, checkpoint, &mut vars)?;
+            assert_eq!(StoryEvent::DialogueComplete, event);
+        }
+
+        assert!(!events.iter().any(|event| matches!(
+            event,
+            StoryEvent::ShowLine { key, .. } if key == "line:after_stop"
+        )));
+        assert_eq!(1.0, story.visit_count(&vars, "Start"));
+
+        Ok(())
+    }
 }
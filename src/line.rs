@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::path::Path;
+
+use thiserror::Error;
+
+use crate::markup::{MarkupAttribute, MarkupParseResult, CHARACTER_ATTRIBUTE};
+
+mod localization;
+
+pub use localization::Localization;
+
+/// The text and metadata of a single line, as written by the Yarn Spinner compiler to its
+/// `-Lines.csv` and `-Metadata.csv` tables.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LineInfo {
+    /// The text of the line, which may contain `{0}`-style substitution markers.
+    pub text: String,
+
+    /// The source file the line was compiled from.
+    pub file: String,
+
+    /// The name of the node the line appears in.
+    pub node: String,
+
+    /// The line number of the line within [file].
+    pub line_number: usize,
+
+    /// Any hashtags attached to the line, without the leading `#`.
+    pub tags: Vec<String>,
+}
+
+/// A line that is ready to be shown, with its substitutions applied, its markup parsed and its
+/// speaker separated from its text.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResolvedLine {
+    pub id: String,
+
+    /// The plain text of the line, without the name of its speaker.
+    pub text: String,
+
+    /// The name of the character speaking the line, if it has one.
+    pub speaker: Option<String>,
+
+    /// The markup attributes of [text].
+    pub attributes: Vec<MarkupAttribute>,
+
+    pub tags: Vec<String>,
+}
+
+impl ResolvedLine {
+    /// Create a resolved line from the parsed text of the line identified by [id], removing the
+    /// range covered by its `character` attribute from the text.
+    #[must_use]
+    pub fn new<S: Into<String>>(id: S, markup: &MarkupParseResult, tags: Vec<String>) -> Self {
+        let speaker = markup.character_name().map(str::to_string);
+        let markup = match markup.attribute(CHARACTER_ATTRIBUTE) {
+            Some(character) => markup.delete_range(character),
+            None => markup.clone(),
+        };
+
+        Self {
+            id: id.into(),
+            text: markup.text,
+            speaker,
+            attributes: markup.attributes,
+            tags,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum LineError {
+    #[error("i/o error occurred when loading line table")]
+    Io(#[from] std::io::Error),
+
+    #[error("line table is missing the '{0}' column")]
+    MissingColumn(&'static str),
+
+    #[error("unterminated quoted field starting on line {0}")]
+    UnterminatedQuote(usize),
+
+    #[error("invalid line number '{1}' on line {0}")]
+    InvalidLineNumber(usize, String),
+}
+
+/// Provides the text of lines for the keys carried by `ShowLine` events and options.
+#[derive(Clone, Debug, Default)]
+pub struct LineProvider {
+    lines: HashMap<String, LineInfo>,
+
+    /// Tags loaded from metadata by line id, kept apart from [lines] so that metadata for a
+    /// line without text does not create an entry for it.
+    tags: HashMap<String, Vec<String>>,
+}
+
+impl LineProvider {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the lines table at [lines] and, if given, the metadata table at [metadata].
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if either file could not be read or is not a valid table.
+    pub fn from_files<L, M>(lines: L, metadata: Option<M>) -> Result<Self, LineError>
+    where
+        L: AsRef<Path>,
+        M: AsRef<Path>,
+    {
+        let mut provider = Self::new();
+        provider.load_lines(&read_to_string(lines)?)?;
+
+        if let Some(metadata) = metadata {
+            provider.load_metadata(&read_to_string(metadata)?)?;
+        }
+
+        Ok(provider)
+    }
+
+    /// Load lines from a table with `id`, `text`, `file`, `node` and `lineNumber` columns,
+    /// replacing the text of any lines that were already loaded.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the table is malformed or is missing the `id` or `text` columns.
+    pub fn load_lines(&mut self, csv: &str) -> Result<(), LineError> {
+        let table = Table::parse(csv)?;
+        let id = table.column("id")?;
+        let text = table.column("text")?;
+        let file = table.optional_column("file");
+        let node = table.optional_column("node");
+        let line_number = table.optional_column("lineNumber");
+
+        for (row, record) in table.records() {
+            let id = field(record, id);
+            let line = self
+                .lines
+                .entry(id.to_string())
+                .or_insert_with(|| LineInfo {
+                    tags: self.tags.get(id).cloned().unwrap_or_default(),
+                    ..LineInfo::default()
+                });
+            line.text = field(record, text).to_string();
+            line.file = file
+                .map(|c| field(record, c).to_string())
+                .unwrap_or_default();
+            line.node = node
+                .map(|c| field(record, c).to_string())
+                .unwrap_or_default();
+            line.line_number = parse_line_number(row, record, line_number)?;
+        }
+
+        Ok(())
+    }
+
+    /// Load line tags from a table with `id`, `node`, `lineNumber` and `tags` columns.
+    ///
+    /// Tags may be separated by spaces within the `tags` column or spread over any further
+    /// columns of the row. Tags for lines that have not been loaded are kept until their text
+    /// is loaded, but do not make those lines resolvable.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the table is malformed or is missing the `id` or `tags` columns.
+    pub fn load_metadata(&mut self, csv: &str) -> Result<(), LineError> {
+        let table = Table::parse(csv)?;
+        let id = table.column("id")?;
+        let tags = table.column("tags")?;
+        let node = table.optional_column("node");
+        let line_number = table.optional_column("lineNumber");
+
+        for (row, record) in table.records() {
+            let id = field(record, id);
+            let line_tags: Vec<String> = record
+                .iter()
+                .skip(tags)
+                .flat_map(|tags| tags.split_whitespace())
+                .map(|tag| tag.trim_start_matches('#').to_string())
+                .collect();
+
+            if let Some(line) = self.lines.get_mut(id) {
+                if line.node.is_empty() {
+                    line.node = node
+                        .map(|c| field(record, c).to_string())
+                        .unwrap_or_default();
+                }
+                if line.line_number == 0 {
+                    line.line_number = parse_line_number(row, record, line_number)?;
+                }
+
+                line.tags.clone_from(&line_tags);
+            }
+
+            self.tags.insert(id.to_string(), line_tags);
+        }
+
+        Ok(())
+    }
+
+    /// Get everything known about the line identified by [id].
+    pub fn line<S: AsRef<str>>(&self, id: S) -> Option<&LineInfo> {
+        self.lines.get(id.as_ref())
+    }
+
+    /// Get the unsubstituted text of the line identified by [id].
+    pub fn text<S: AsRef<str>>(&self, id: S) -> Option<&str> {
+        self.line(id).map(|line| line.text.as_str())
+    }
+
+    /// Get the tags of the line identified by [id], which are empty if the line is unknown.
+    pub fn tags<S: AsRef<str>>(&self, id: S) -> &[String] {
+        self.line(id).map_or(&[], |line| &line.tags)
+    }
+
+    /// Get the text of the line identified by [id] with its [substitutions] applied.
+    pub fn resolve<S: AsRef<str>>(&self, id: S, substitutions: &[String]) -> Option<String> {
+        self.text(id).map(|text| substitute(text, substitutions))
+    }
+
+    /// Iterate over the ids of all known lines alongside their information.
+    pub fn lines(&self) -> impl Iterator<Item = (&str, &LineInfo)> {
+        self.lines.iter().map(|(id, line)| (id.as_str(), line))
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+}
+
+/// Replace each `{n}` marker in [text] with the `n`th entry of [substitutions].
+///
+/// Markers that do not refer to a substitution are left as they are.
+#[must_use]
+pub fn substitute(text: &str, substitutions: &[String]) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let replacement = rest.find('}').and_then(|end| {
+            let index: usize = rest[1..end].parse().ok()?;
+            Some((substitutions.get(index)?, end))
+        });
+
+        match replacement {
+            Some((value, end)) => {
+                result.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                result.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    result.push_str(rest);
+    result
+}
+
+fn field(record: &[String], column: usize) -> &str {
+    record.get(column).map_or("", String::as_str)
+}
+
+fn parse_line_number(
+    row: usize,
+    record: &[String],
+    column: Option<usize>,
+) -> Result<usize, LineError> {
+    match column.map(|c| field(record, c).trim()) {
+        None | Some("") => Ok(0),
+        Some(value) => value
+            .parse()
+            .map_err(|_| LineError::InvalidLineNumber(row, value.to_string())),
+    }
+}
+
+/// A parsed CSV table, following RFC 4180 quoting.
+struct Table {
+    header: Vec<String>,
+
+    /// Each record after the header, alongside the line it starts on.
+    records: Vec<(usize, Vec<String>)>,
+}
+
+impl Table {
+    fn parse(csv: &str) -> Result<Self, LineError> {
+        let mut records = Vec::new();
+        let mut record = Vec::new();
+        let mut value = String::new();
+        let mut line = 1;
+        let mut record_line = 1;
+        let mut chars = csv
+            .strip_prefix('\u{feff}')
+            .unwrap_or(csv)
+            .chars()
+            .peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if value.is_empty() => {
+                    let start = line;
+                    loop {
+                        match chars.next() {
+                            Some('"') if chars.peek() == Some(&'"') => {
+                                chars.next();
+                                value.push('"');
+                            }
+                            Some('"') => break,
+                            Some(c) => {
+                                if c == '\n' {
+                                    line += 1;
+                                }
+                                value.push(c);
+                            }
+                            None => return Err(LineError::UnterminatedQuote(start)),
+                        }
+                    }
+                }
+                ',' => record.push(std::mem::take(&mut value)),
+                '\r' if chars.peek() == Some(&'\n') => {}
+                '\n' => {
+                    record.push(std::mem::take(&mut value));
+                    records.push((record_line, std::mem::take(&mut record)));
+                    line += 1;
+                    record_line = line;
+                }
+                c => value.push(c),
+            }
+        }
+
+        if !value.is_empty() || !record.is_empty() {
+            record.push(value);
+            records.push((record_line, record));
+        }
+
+        records.retain(|(_, record)| !matches!(record.as_slice(), [only] if only.is_empty()));
+
+        let mut records = records.into_iter();
+        let header = records.next().map(|(_, header)| header).unwrap_or_default();
+
+        Ok(Self {
+            header,
+            records: records.collect(),
+        })
+    }
+
+    fn optional_column(&self, name: &str) -> Option<usize> {
+        self.header.iter().position(|column| column == name)
+    }
+
+    fn column(&self, name: &'static str) -> Result<usize, LineError> {
+        self.optional_column(name)
+            .ok_or(LineError::MissingColumn(name))
+    }
+
+    fn records(&self) -> impl Iterator<Item = (usize, &[String])> {
+        self.records
+            .iter()
+            .map(|(line, record)| (*line, record.as_slice()))
+    }
+}
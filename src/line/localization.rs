@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use super::{substitute, LineInfo, LineProvider};
+
+/// The line tables of a story in each of the locales it has been translated into.
+///
+/// Lines that are missing from a locale are looked up through its fallback chain, which ends
+/// with the base locale the story was written in. Unless a chain is set explicitly with
+/// [`Localization::with_fallbacks`], a locale such as `pt-BR` falls back to `pt` and then to
+/// the base locale.
+#[derive(Clone, Debug)]
+pub struct Localization {
+    base: String,
+    tables: HashMap<String, LineProvider>,
+    fallbacks: HashMap<String, Vec<String>>,
+}
+
+impl Localization {
+    /// Create a localization whose base locale [base] has the text given by [lines].
+    #[must_use]
+    pub fn new<S: Into<String>>(base: S, lines: LineProvider) -> Self {
+        let base = base.into();
+
+        Self {
+            tables: HashMap::from([(base.clone(), lines)]),
+            base,
+            fallbacks: HashMap::new(),
+        }
+    }
+
+    /// Add the text of [locale], replacing any text previously added for it.
+    #[must_use]
+    pub fn with_locale<S: Into<String>>(mut self, locale: S, lines: LineProvider) -> Self {
+        self.tables.insert(locale.into(), lines);
+        self
+    }
+
+    /// Look up lines missing from [locale] in each locale of [chain] in turn, before falling
+    /// back to the base locale.
+    #[must_use]
+    pub fn with_fallbacks<S, I>(mut self, locale: S, chain: I) -> Self
+    where
+        S: Into<String>,
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.fallbacks
+            .insert(locale.into(), chain.into_iter().map(Into::into).collect());
+        self
+    }
+
+    #[must_use]
+    pub fn base_locale(&self) -> &str {
+        &self.base
+    }
+
+    /// Check if any text has been added for [locale].
+    pub fn contains<S: AsRef<str>>(&self, locale: S) -> bool {
+        self.tables.contains_key(locale.as_ref())
+    }
+
+    /// Iterate over every locale that text has been added for.
+    pub fn locales(&self) -> impl Iterator<Item = &str> {
+        self.tables.keys().map(String::as_str)
+    }
+
+    /// Get the line table of [locale] alone, without any fallbacks.
+    pub fn lines<S: AsRef<str>>(&self, locale: S) -> Option<&LineProvider> {
+        self.tables.get(locale.as_ref())
+    }
+
+    /// Get the tags of the line identified by [id], as given by the base locale.
+    pub fn tags<S: AsRef<str>>(&self, id: S) -> &[String] {
+        self.tables[&self.base].tags(id)
+    }
+
+    /// Check if lines can be shown in [locale], either from its own text or from text found
+    /// through its fallbacks before reaching the base locale.
+    ///
+    /// A locale such as `de-AT` is supported when it has text of its own, when a fallback set
+    /// with [`Localization::with_fallbacks`] has text, or when its language, such as `de`, has
+    /// text.
+    pub fn supports<S: AsRef<str>>(&self, locale: S) -> bool {
+        self.candidates(locale.as_ref())
+            .iter()
+            .any(|locale| self.contains(locale))
+    }
+
+    /// Get [locale] followed by the locales it falls back to, before the base locale.
+    fn candidates<'a>(&'a self, locale: &'a str) -> Vec<&'a str> {
+        let mut chain = vec![locale];
+
+        match self.fallbacks.get(locale) {
+            Some(fallbacks) => chain.extend(fallbacks.iter().map(String::as_str)),
+            None => chain.extend(
+                locale
+                    .char_indices()
+                    .rev()
+                    .filter(|(_, c)| matches!(c, '-' | '_'))
+                    .map(|(index, _)| &locale[..index]),
+            ),
+        }
+
+        chain
+    }
+
+    /// Get the locales searched for lines requested in [locale], in order. Locales without any
+    /// text are skipped.
+    #[must_use]
+    pub fn fallback_chain<'a>(&'a self, locale: &'a str) -> Vec<&'a str> {
+        let mut chain = self.candidates(locale);
+        chain.push(&self.base);
+
+        let mut seen = Vec::with_capacity(chain.len());
+        chain.retain(|locale| {
+            let keep = self.contains(locale) && !seen.contains(locale);
+            seen.push(*locale);
+            keep
+        });
+
+        chain
+    }
+
+    /// Get the line identified by [id] as it should be shown in [locale].
+    pub fn line<S: AsRef<str>>(&self, locale: &str, id: S) -> Option<&LineInfo> {
+        self.fallback_chain(locale)
+            .into_iter()
+            .find_map(|locale| self.tables[locale].line(id.as_ref()))
+    }
+
+    /// Get the text of the line identified by [id] in [locale] with its [substitutions]
+    /// applied.
+    pub fn resolve<S: AsRef<str>>(
+        &self,
+        locale: &str,
+        id: S,
+        substitutions: &[String],
+    ) -> Option<String> {
+        self.line(locale, id)
+            .map(|line| substitute(&line.text, substitutions))
+    }
+
+    /// Get the ids of lines in the base locale that [locale] has no text for, in sorted order.
+    ///
+    /// Lines that are missing are still shown using the fallback chain of [locale], so this
+    /// reports what is left to translate rather than what cannot be shown.
+    pub fn missing_keys<S: AsRef<str>>(&self, locale: S) -> Vec<&str> {
+        let empty = LineProvider::default();
+        let lines = self.tables.get(locale.as_ref()).unwrap_or(&empty);
+
+        let mut missing: Vec<_> = self.tables[&self.base]
+            .lines()
+            .map(|(id, _)| id)
+            .filter(|id| lines.line(id).is_none())
+            .collect();
+        missing.sort_unstable();
+        missing
+    }
+}
@@ -0,0 +1,615 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+use thiserror::Error;
+
+mod plural;
+
+pub use plural::{cardinal, ordinal, PluralCase};
+use plural::{cardinal_of, ordinal_of, Operands};
+
+/// The name of the attribute added to lines that begin with a character's name, such as
+/// `Sally: Hi.`
+pub const CHARACTER_ATTRIBUTE: &str = "character";
+
+/// The name of the property of the [`CHARACTER_ATTRIBUTE`] that holds the character's name.
+pub const CHARACTER_ATTRIBUTE_NAME_PROPERTY: &str = "name";
+
+/// The attribute whose contents are not parsed for markup.
+pub const NO_MARKUP_ATTRIBUTE: &str = "nomarkup";
+
+/// The property of a replacement marker that holds the value used to choose its replacement.
+pub const VALUE_PROPERTY: &str = "value";
+
+/// The text in a replacement that is replaced by the marker's value.
+pub const VALUE_PLACEHOLDER: char = '%';
+
+/// The property of a self-closing marker that controls whether a single whitespace character
+/// following it is removed.
+pub const TRIM_WHITESPACE_PROPERTY: &str = "trimwhitespace";
+
+/// The value of a property given to a markup attribute.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MarkupValue {
+    Integer(i32),
+    Float(f32),
+    String(String),
+    Bool(bool),
+}
+
+impl Display for MarkupValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Integer(value) => write!(f, "{value}"),
+            Self::Float(value) => write!(f, "{value}"),
+            Self::String(value) => write!(f, "{value}"),
+            Self::Bool(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+/// A range of the plain text of a line that was marked up with an attribute.
+///
+/// Positions and lengths are measured in characters rather than bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MarkupAttribute {
+    pub name: String,
+
+    /// The position of the start of the attribute within the plain text.
+    pub position: usize,
+
+    /// The number of characters of plain text the attribute covers.
+    pub length: usize,
+
+    /// The position of the attribute's opening marker within the original text.
+    pub source_position: usize,
+
+    pub properties: HashMap<String, MarkupValue>,
+}
+
+impl MarkupAttribute {
+    pub fn property<S: AsRef<str>>(&self, name: S) -> Option<&MarkupValue> {
+        self.properties.get(name.as_ref())
+    }
+}
+
+/// The plain text of a line with its markup removed, and the attributes that markup described.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MarkupParseResult {
+    pub text: String,
+
+    /// The attributes of the line, ordered by their position in [text].
+    pub attributes: Vec<MarkupAttribute>,
+}
+
+impl MarkupParseResult {
+    /// Get the first attribute named [name].
+    pub fn attribute<S: AsRef<str>>(&self, name: S) -> Option<&MarkupAttribute> {
+        self.attributes
+            .iter()
+            .find(|attribute| attribute.name == name.as_ref())
+    }
+
+    /// The name of the character speaking the line, if it starts with one.
+    #[must_use]
+    pub fn character_name(&self) -> Option<&str> {
+        match self
+            .attribute(CHARACTER_ATTRIBUTE)?
+            .property(CHARACTER_ATTRIBUTE_NAME_PROPERTY)?
+        {
+            MarkupValue::String(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Get the plain text covered by [attribute].
+    #[must_use]
+    pub fn text_for_attribute(&self, attribute: &MarkupAttribute) -> &str {
+        let start = byte_offset(&self.text, attribute.position);
+        let end = byte_offset(&self.text, attribute.position + attribute.length);
+        &self.text[start..end]
+    }
+
+    /// Remove the plain text covered by [attribute] along with the attribute itself, moving
+    /// or shortening every other attribute to match.
+    ///
+    /// This can be used to remove the character name from the start of a line.
+    #[must_use]
+    pub fn delete_range(&self, attribute: &MarkupAttribute) -> Self {
+        let start = attribute.position;
+        let end = start + attribute.length;
+
+        let mut text = self.text.clone();
+        text.replace_range(
+            byte_offset(&self.text, start)..byte_offset(&self.text, end),
+            "",
+        );
+
+        let attributes = self
+            .attributes
+            .iter()
+            .filter(|other| *other != attribute)
+            .map(|other| {
+                let shift = |position: usize| {
+                    if position <= start {
+                        position
+                    } else {
+                        position.max(end) - attribute.length
+                    }
+                };
+
+                let position = shift(other.position);
+                MarkupAttribute {
+                    position,
+                    length: shift(other.position + other.length) - position,
+                    ..other.clone()
+                }
+            })
+            .collect();
+
+        Self { text, attributes }
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum MarkupError {
+    #[error("unterminated marker starting at position {0}")]
+    UnterminatedMarker(usize),
+
+    #[error("expected {1} at position {0}")]
+    Expected(usize, &'static str),
+
+    #[error("unterminated string starting at position {0}")]
+    UnterminatedString(usize),
+
+    #[error("close marker for '{0}' at position {1} does not match an open marker")]
+    UnexpectedCloseMarker(String, usize),
+
+    #[error("marker '{0}' at position {1} is never closed")]
+    UnclosedMarker(String, usize),
+
+    #[error("'{0}' marker at position {2} has no '{1}' property")]
+    MissingProperty(String, String, usize),
+
+    #[error("'{0}' marker at position {2} has a value of '{1}', which is not a number")]
+    InvalidValue(String, MarkupValue, usize),
+}
+
+/// Parse the markup in [input], which should already have had its substitutions applied.
+///
+/// Markers such as `[b]` open an attribute that is closed by the matching `[/b]`, and `[/]`
+/// closes every open attribute. Self-closing markers such as `[pause=500/]` produce an
+/// attribute with no length, and remove a single whitespace character that follows them if
+/// they are at the start of the line or follow whitespace, unless given the property
+/// `trimwhitespace=false`. Brackets can be escaped with `\[` and `\]`, and the contents of
+/// `[nomarkup]` are not parsed.
+///
+/// If the line begins with a character's name followed by a colon and there is no explicit
+/// `character` attribute, one is added covering the name, the colon and any whitespace after
+/// it. Colons escaped with `\:` do not end a character's name.
+///
+/// Replacement markers are evaluated using the plural rules of English. See
+/// [`parse_localized`].
+///
+/// # Errors
+///
+/// Returns `Err` if a marker is malformed, or close markers do not match open markers.
+pub fn parse(input: &str) -> Result<MarkupParseResult, MarkupError> {
+    parse_localized(input, "en")
+}
+
+/// Parse the markup in [input] as with [parse], evaluating replacement markers for [locale].
+///
+/// Replacement markers are self-closing markers that are replaced by one of their properties
+/// rather than producing an attribute:
+///
+/// - `[select value={0} m="he" f="she"/]` is replaced by the property named by its value.
+/// - `[plural value={0} one="apple" other="apples"/]` is replaced by the property named by the
+///   cardinal [`PluralCase`] of its value in [locale].
+/// - `[ordinal value={0} one="%st" two="%nd" few="%rd" other="%th"/]` is replaced by the
+///   property named by the ordinal [`PluralCase`] of its value in [locale].
+///
+/// Any `%` in the replacement is replaced by the value itself.
+///
+/// # Errors
+///
+/// See [parse]. Also returns `Err` if a replacement marker has no `value`, or no property for
+/// its value, or the value of a `plural` or `ordinal` marker is not a number.
+pub fn parse_localized(input: &str, locale: &str) -> Result<MarkupParseResult, MarkupError> {
+    Parser {
+        input: input.chars().collect(),
+        locale,
+        position: 0,
+        text: String::new(),
+        length: 0,
+        colon: None,
+        value_text: String::new(),
+    }
+    .parse()
+}
+
+enum Marker {
+    Open(String, HashMap<String, MarkupValue>),
+    Close(String),
+    CloseAll,
+    SelfClosing(String, HashMap<String, MarkupValue>),
+}
+
+struct Parser<'l> {
+    input: Vec<char>,
+
+    /// The locale used to evaluate replacement markers.
+    locale: &'l str,
+
+    position: usize,
+
+    /// The plain text produced so far.
+    text: String,
+
+    /// The length of [text] in characters.
+    length: usize,
+
+    /// The position in [text] of the first colon that was not escaped.
+    colon: Option<usize>,
+
+    /// The source text of the `value` property of the most recent marker, from which the
+    /// operands of plural rules are derived.
+    value_text: String,
+}
+
+impl Parser<'_> {
+    fn parse(mut self) -> Result<MarkupParseResult, MarkupError> {
+        let mut open: Vec<MarkupAttribute> = vec![];
+        let mut attributes = vec![];
+
+        while let Some(c) = self.next() {
+            match c {
+                '\\' if matches!(self.peek(), Some('[' | ']' | ':')) => {
+                    if let Some(escaped) = self.next() {
+                        self.push(escaped);
+                    }
+                }
+                '[' => {
+                    let source_position = self.position - 1;
+                    let position = self.length;
+                    let attribute = move |name, properties| MarkupAttribute {
+                        name,
+                        position,
+                        length: 0,
+                        source_position,
+                        properties,
+                    };
+
+                    match self.marker(source_position)? {
+                        Marker::Open(name, properties) if name == NO_MARKUP_ATTRIBUTE => {
+                            let mut attribute = attribute(name, properties);
+                            self.no_markup(source_position)?;
+                            attribute.length = self.length - attribute.position;
+                            attributes.push(attribute);
+                        }
+                        Marker::Open(name, properties) => {
+                            open.push(attribute(name, properties));
+                        }
+                        Marker::SelfClosing(name, properties)
+                            if matches!(name.as_str(), "select" | "plural" | "ordinal") =>
+                        {
+                            for c in self
+                                .replacement(&name, &properties, source_position)?
+                                .chars()
+                            {
+                                self.push(c);
+                            }
+                        }
+                        Marker::SelfClosing(name, properties) => {
+                            let trim = !matches!(
+                                properties.get(TRIM_WHITESPACE_PROPERTY),
+                                Some(MarkupValue::Bool(false))
+                            );
+                            attributes.push(attribute(name, properties));
+
+                            let after_whitespace = !matches!(
+                                self.text.chars().last(),
+                                Some(c) if !c.is_whitespace()
+                            );
+                            if trim
+                                && after_whitespace
+                                && matches!(self.peek(), Some(c) if c.is_whitespace())
+                            {
+                                self.position += 1;
+                            }
+                        }
+                        Marker::Close(name) => {
+                            let index = open
+                                .iter()
+                                .rposition(|attribute| attribute.name == name)
+                                .ok_or(MarkupError::UnexpectedCloseMarker(
+                                name,
+                                source_position,
+                            ))?;
+
+                            let mut attribute = open.remove(index);
+                            attribute.length = self.length - attribute.position;
+                            attributes.push(attribute);
+                        }
+                        Marker::CloseAll => {
+                            for mut attribute in open.drain(..).rev() {
+                                attribute.length = self.length - attribute.position;
+                                attributes.push(attribute);
+                            }
+                        }
+                    }
+                }
+                ':' if self.colon.is_none() => {
+                    self.colon = Some(self.length);
+                    self.push(':');
+                }
+                c => self.push(c),
+            }
+        }
+
+        if let Some(attribute) = open.into_iter().next() {
+            return Err(MarkupError::UnclosedMarker(
+                attribute.name,
+                attribute.source_position,
+            ));
+        }
+
+        if !attributes
+            .iter()
+            .any(|attribute| attribute.name == CHARACTER_ATTRIBUTE)
+        {
+            if let Some(attribute) = self.character_attribute() {
+                attributes.push(attribute);
+            }
+        }
+
+        attributes.sort_by_key(|attribute| attribute.position);
+
+        Ok(MarkupParseResult {
+            text: self.text,
+            attributes,
+        })
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += 1;
+        Some(c)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input.get(self.position).copied()
+    }
+
+    fn push(&mut self, c: char) {
+        self.text.push(c);
+        self.length += 1;
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(c) if c.is_whitespace()) {
+            self.position += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: char, description: &'static str) -> Result<(), MarkupError> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(_) => Err(MarkupError::Expected(self.position - 1, description)),
+            None => Err(MarkupError::Expected(self.position, description)),
+        }
+    }
+
+    /// Parse a marker following its opening `[`.
+    fn marker(&mut self, start: usize) -> Result<Marker, MarkupError> {
+        if self.input[self.position..].iter().all(|c| *c != ']') {
+            return Err(MarkupError::UnterminatedMarker(start));
+        }
+
+        self.skip_whitespace();
+        if self.peek() == Some('/') {
+            self.position += 1;
+            self.skip_whitespace();
+
+            if self.peek() == Some(']') {
+                self.position += 1;
+                return Ok(Marker::CloseAll);
+            }
+
+            let name = self.name()?;
+            self.skip_whitespace();
+            self.expect(']', "']'")?;
+            return Ok(Marker::Close(name));
+        }
+
+        let name = self.name()?;
+        let mut properties = HashMap::new();
+
+        if self.peek() == Some('=') {
+            self.position += 1;
+            properties.insert(name.clone(), self.value()?);
+        }
+
+        loop {
+            self.skip_whitespace();
+
+            match self.peek() {
+                Some(']') => {
+                    self.position += 1;
+                    return Ok(Marker::Open(name, properties));
+                }
+                Some('/') => {
+                    self.position += 1;
+                    self.expect(']', "']'")?;
+                    return Ok(Marker::SelfClosing(name, properties));
+                }
+                _ => {
+                    let property = self.name()?;
+                    self.skip_whitespace();
+                    self.expect('=', "'='")?;
+                    self.skip_whitespace();
+
+                    let start = self.position;
+                    let value = self.value()?;
+                    if property == VALUE_PROPERTY {
+                        self.value_text = self.input[start..self.position].iter().collect();
+                    }
+
+                    properties.insert(property, value);
+                }
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<String, MarkupError> {
+        let start = self.position;
+        while matches!(self.peek(), Some(c) if c.is_alphanumeric() || c == '_' || c == '-') {
+            self.position += 1;
+        }
+
+        if start == self.position {
+            return Err(MarkupError::Expected(start, "a name"));
+        }
+
+        Ok(self.input[start..self.position].iter().collect())
+    }
+
+    fn value(&mut self) -> Result<MarkupValue, MarkupError> {
+        let start = self.position;
+
+        if self.peek() == Some('"') {
+            self.position += 1;
+            let mut value = String::new();
+
+            loop {
+                match self.next() {
+                    Some('"') => return Ok(MarkupValue::String(value)),
+                    Some('\\') if matches!(self.peek(), Some('"' | '\\')) => {
+                        value.extend(self.next());
+                    }
+                    Some(c) => value.push(c),
+                    None => return Err(MarkupError::UnterminatedString(start)),
+                }
+            }
+        }
+
+        while matches!(self.peek(), Some(c) if !c.is_whitespace() && c != ']')
+            && !(self.peek() == Some('/') && self.input.get(self.position + 1) == Some(&']'))
+        {
+            self.position += 1;
+        }
+
+        let value: String = self.input[start..self.position].iter().collect();
+        if value.is_empty() {
+            return Err(MarkupError::Expected(start, "a value"));
+        }
+
+        if let Ok(value) = value.parse() {
+            return Ok(MarkupValue::Bool(value));
+        }
+
+        // Only values that look like numbers are parsed as numbers, so that words such as
+        // `inf` and `nan` remain strings.
+        if matches!(value.chars().next(), Some(c) if c.is_ascii_digit() || c == '-' || c == '.') {
+            if let Ok(value) = value.parse() {
+                return Ok(MarkupValue::Integer(value));
+            }
+            if let Ok(value) = value.parse() {
+                return Ok(MarkupValue::Float(value));
+            }
+        }
+
+        Ok(MarkupValue::String(value))
+    }
+
+    /// Evaluate the replacement marker [name] with the given [properties].
+    fn replacement(
+        &self,
+        name: &str,
+        properties: &HashMap<String, MarkupValue>,
+        position: usize,
+    ) -> Result<String, MarkupError> {
+        let property = |property: &str| {
+            properties.get(property).ok_or_else(|| {
+                MarkupError::MissingProperty(name.to_string(), property.to_string(), position)
+            })
+        };
+
+        let value = property(VALUE_PROPERTY)?;
+        // Plural rules depend on how a number is written as well as its value, so that `1.0` is
+        // not treated like `1`.
+        let operands = || {
+            let n = match value {
+                MarkupValue::Integer(n) => f64::from(*n),
+                MarkupValue::Float(n) => f64::from(*n),
+                _ => {
+                    return Err(MarkupError::InvalidValue(
+                        name.to_string(),
+                        value.clone(),
+                        position,
+                    ))
+                }
+            };
+
+            Ok(Operands::parse(&self.value_text).unwrap_or_else(|| Operands::new(n)))
+        };
+
+        let case = match name {
+            "plural" => cardinal_of(self.locale, &operands()?).as_str().to_string(),
+            "ordinal" => ordinal_of(self.locale, &operands()?).as_str().to_string(),
+            _ => value.to_string(),
+        };
+
+        Ok(property(&case)?
+            .to_string()
+            .replace(VALUE_PLACEHOLDER, &value.to_string()))
+    }
+
+    /// Copy text up to the `[/nomarkup]` marker that closes a `[nomarkup]` marker verbatim.
+    fn no_markup(&mut self, start: usize) -> Result<(), MarkupError> {
+        let close: Vec<char> = format!("[/{NO_MARKUP_ATTRIBUTE}]").chars().collect();
+
+        while !self.input[self.position..].starts_with(&close) {
+            match self.next() {
+                Some(c) => self.push(c),
+                None => {
+                    return Err(MarkupError::UnclosedMarker(
+                        NO_MARKUP_ATTRIBUTE.to_string(),
+                        start,
+                    ))
+                }
+            }
+        }
+
+        self.position += close.len();
+        Ok(())
+    }
+
+    fn character_attribute(&self) -> Option<MarkupAttribute> {
+        let colon = self.colon?;
+        let name: String = self.text.chars().take(colon).collect();
+        let whitespace = self
+            .text
+            .chars()
+            .skip(colon + 1)
+            .take_while(|c| c.is_whitespace())
+            .count();
+
+        Some(MarkupAttribute {
+            name: CHARACTER_ATTRIBUTE.to_string(),
+            position: 0,
+            length: colon + 1 + whitespace,
+            source_position: 0,
+            properties: HashMap::from([(
+                CHARACTER_ATTRIBUTE_NAME_PROPERTY.to_string(),
+                MarkupValue::String(name),
+            )]),
+        })
+    }
+}
+
+fn byte_offset(text: &str, position: usize) -> usize {
+    text.char_indices()
+        .nth(position)
+        .map_or(text.len(), |(offset, _)| offset)
+}
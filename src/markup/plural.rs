@@ -0,0 +1,195 @@
+//! Plural rules from the Unicode CLDR, used to choose between the cases of `plural` and
+//! `ordinal` markers.
+
+/// A plural category defined by the CLDR.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PluralCase {
+    Zero,
+    One,
+    Two,
+    Few,
+    Many,
+    Other,
+}
+
+impl PluralCase {
+    /// The name of this case, as used for the properties of `plural` and `ordinal` markers.
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Zero => "zero",
+            Self::One => "one",
+            Self::Two => "two",
+            Self::Few => "few",
+            Self::Many => "many",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// The operands of a number that plural rules are defined in terms of.
+pub(crate) struct Operands {
+    /// The absolute value of the number.
+    n: f64,
+
+    /// The integer digits of the number.
+    i: u64,
+
+    /// The number of visible fraction digits, with trailing zeros.
+    v: usize,
+}
+
+impl Operands {
+    /// Get the operands of [text], a number written in decimal notation such as `1.50`, keeping
+    /// the fraction digits it was written with.
+    pub(crate) fn parse(text: &str) -> Option<Self> {
+        let digits = text.trim_start_matches(['+', '-']);
+        let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+
+        if !integer
+            .chars()
+            .chain(fraction.chars())
+            .all(|c| c.is_ascii_digit())
+        {
+            return None;
+        }
+
+        Some(Self {
+            n: text.parse::<f64>().ok()?.abs(),
+            i: if integer.is_empty() {
+                0
+            } else {
+                integer.parse().ok()?
+            },
+            v: fraction.len(),
+        })
+    }
+
+    pub(crate) fn new(n: f64) -> Self {
+        let n = n.abs();
+        let text = format!("{n}");
+        let v = text
+            .split_once('.')
+            .map_or(0, |(_, fraction)| fraction.len());
+
+        Self {
+            n,
+            i: n.trunc() as u64,
+            v,
+        }
+    }
+
+    fn is(&self, value: u64) -> bool {
+        self.n == value as f64
+    }
+}
+
+/// Get the language of [locale], such as `pt` for `pt-BR`.
+fn language(locale: &str) -> String {
+    locale
+        .split(['-', '_'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+/// Get the cardinal plural case of [n] in [locale], as used to choose between the cases of
+/// `plural` markers such as "1 apple" and "2 apples".
+///
+/// Some rules depend on the fraction digits a number is written with, which [n] does not
+/// carry, so `1.0` is treated like `1`. Markers keep the digits of their `value` instead.
+///
+/// Locales without known rules use the rules for English.
+#[must_use]
+pub fn cardinal(locale: &str, n: f64) -> PluralCase {
+    cardinal_of(locale, &Operands::new(n))
+}
+
+/// Get the cardinal plural case of the number with the operands [o] in [locale].
+pub(crate) fn cardinal_of(locale: &str, o: &Operands) -> PluralCase {
+    use PluralCase::{Few, Many, One, Other, Two, Zero};
+
+    let (i, v) = (o.i, o.v);
+    let integer = v == 0;
+
+    match language(locale).as_str() {
+        "ja" | "zh" | "ko" | "vi" | "th" | "id" | "ms" | "lo" | "my" => Other,
+        "fr" | "es" | "it" | "pt" | "ca" if i != 0 && i % 1_000_000 == 0 && integer => Many,
+        "fr" if i <= 1 => One,
+        "pt" if locale.eq_ignore_ascii_case("pt-PT") || locale.eq_ignore_ascii_case("pt_PT") => {
+            if i == 1 && integer {
+                One
+            } else {
+                Other
+            }
+        }
+        "pt" if i <= 1 => One,
+        "it" | "ca" if i == 1 && integer => One,
+        "fr" | "pt" | "it" | "ca" => Other,
+        "es" | "tr" | "el" | "hu" if o.is(1) => One,
+        "es" | "tr" | "el" | "hu" => Other,
+        "ru" | "uk" | "be" if integer && i % 10 == 1 && i % 100 != 11 => One,
+        "ru" | "uk" | "be"
+            if integer && (2..=4).contains(&(i % 10)) && !(12..=14).contains(&(i % 100)) =>
+        {
+            Few
+        }
+        "ru" | "uk" | "be" if integer => Many,
+        "ru" | "uk" | "be" => Other,
+        "pl" if i == 1 && integer => One,
+        "pl" if integer && (2..=4).contains(&(i % 10)) && !(12..=14).contains(&(i % 100)) => Few,
+        "pl" if integer => Many,
+        "pl" => Other,
+        "cs" | "sk" if i == 1 && integer => One,
+        "cs" | "sk" if (2..=4).contains(&i) && integer => Few,
+        "cs" | "sk" if !integer => Many,
+        "cs" | "sk" => Other,
+        "ar" if o.is(0) => Zero,
+        "ar" if o.is(1) => One,
+        "ar" if o.is(2) => Two,
+        "ar" if o.n.fract() == 0.0 && (3..=10).contains(&(i % 100)) => Few,
+        "ar" if o.n.fract() == 0.0 && (11..=99).contains(&(i % 100)) => Many,
+        "ar" => Other,
+        "he" if (i == 1 && integer) || (i == 0 && !integer) => One,
+        "he" if i == 2 && integer => Two,
+        "he" => Other,
+        _ if i == 1 && integer => One,
+        _ => Other,
+    }
+}
+
+/// Get the ordinal plural case of [n] in [locale], as used to choose between the cases of
+/// `ordinal` markers such as "1st" and "2nd".
+///
+/// Locales without known rules use the rules for English.
+#[must_use]
+pub fn ordinal(locale: &str, n: f64) -> PluralCase {
+    ordinal_of(locale, &Operands::new(n))
+}
+
+/// Get the ordinal plural case of the number with the operands [o] in [locale].
+pub(crate) fn ordinal_of(locale: &str, o: &Operands) -> PluralCase {
+    use PluralCase::{Few, Many, One, Other, Two};
+
+    let integer = o.n.fract() == 0.0;
+    let (n10, n100) = (o.i % 10, o.i % 100);
+
+    match language(locale).as_str() {
+        "de" | "es" | "pt" | "ru" | "uk" | "pl" | "cs" | "sk" | "nl" | "ja" | "zh" | "ko"
+        | "ar" | "he" | "tr" | "el" | "id" | "th" => Other,
+        "fr" if o.is(1) => One,
+        "fr" => Other,
+        "it" if o.is(11) || o.is(8) || o.is(80) || o.is(800) => Many,
+        "it" => Other,
+        "sv" if integer && (n10 == 1 || n10 == 2) && n100 != 11 && n100 != 12 => One,
+        "sv" => Other,
+        "hu" if o.is(1) || o.is(5) => One,
+        "hu" => Other,
+        "vi" if o.is(1) => One,
+        "vi" => Other,
+        _ if integer && n10 == 1 && n100 != 11 => One,
+        _ if integer && n10 == 2 && n100 != 12 => Two,
+        _ if integer && n10 == 3 && n100 != 13 => Few,
+        _ => Other,
+    }
+}
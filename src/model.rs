@@ -5,7 +5,59 @@ pub mod proto {
     include!(concat!(env!("OUT_DIR"), "/yarn.rs"));
 }
 
-pub use proto::{instruction::*, operand::*, *};
+pub use proto::{instruction::*, *};
+
+mod builder;
+
+pub use builder::{NodeBuilder, ProgramBuilder};
+
+/// A value that can be held on the evaluation stack, stored in a variable or passed to a
+/// function.
+///
+/// This mirrors the [`operand::Value`] found in compiled programs, with the addition of
+/// [`Value::NullValue`] for programs that push null.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    StringValue(String),
+    BoolValue(bool),
+    FloatValue(f32),
+    NullValue,
+}
+
+impl From<operand::Value> for Value {
+    fn from(value: operand::Value) -> Self {
+        match value {
+            operand::Value::StringValue(value) => Self::StringValue(value),
+            operand::Value::BoolValue(value) => Self::BoolValue(value),
+            operand::Value::FloatValue(value) => Self::FloatValue(value),
+        }
+    }
+}
+
+impl From<&Operand> for Value {
+    fn from(operand: &Operand) -> Self {
+        operand.value.clone().map_or(Self::NullValue, Self::from)
+    }
+}
+
+impl From<Value> for Operand {
+    fn from(value: Value) -> Self {
+        let value = match value {
+            Value::StringValue(value) => Some(operand::Value::StringValue(value)),
+            Value::BoolValue(value) => Some(operand::Value::BoolValue(value)),
+            Value::FloatValue(value) => Some(operand::Value::FloatValue(value)),
+            Value::NullValue => None,
+        };
+
+        Self { value }
+    }
+}
+
+impl From<()> for Value {
+    fn from(_: ()) -> Self {
+        Self::NullValue
+    }
+}
 
 #[derive(Error, Debug)]
 pub enum ValueError {
@@ -17,13 +69,14 @@ pub enum ValueError {
 }
 
 macro_rules! value_conversion {
-    ($name: path, $ty: ty) => {
+    ($name: path, $ty: ty, $null: expr) => {
         impl TryFrom<Value> for $ty {
             type Error = ValueError;
 
             fn try_from(value: Value) -> Result<$ty, Self::Error> {
                 match value {
                     $name(value) => Ok(value),
+                    Value::NullValue => Ok($null),
                     _ => Err(ValueError::UnexpectedType(stringify!($name), value.clone())),
                 }
             }
@@ -51,12 +104,13 @@ impl TryFrom<Value> for String {
             Value::StringValue(value) => Ok(value),
             Value::FloatValue(value) => Ok(format!("{}", value)),
             Value::BoolValue(value) => Ok(format!("{}", value)),
+            Value::NullValue => Ok("null".to_string()),
         }
     }
 }
 
-value_conversion!(Value::BoolValue, bool);
-value_conversion!(Value::FloatValue, f32);
+value_conversion!(Value::BoolValue, bool, false);
+value_conversion!(Value::FloatValue, f32, 0.0);
 
 pub trait Operands {
     fn at<T>(&self, index: usize) -> Result<T, ValueError>
@@ -72,7 +126,7 @@ impl Operands for Vec<Operand> {
         self.get(index)
             .and_then(|operand| operand.value.clone()) // TODO: avoid clone
             .ok_or(ValueError::Missing)
-            .and_then(std::convert::TryInto::try_into)
+            .and_then(|value| Value::from(value).try_into())
     }
 }
 
@@ -80,6 +134,9 @@ impl Operands for Vec<Operand> {
 pub enum NodeError {
     #[error("the label named by '{0}' could not be resolved")]
     InvalidLabel(String),
+
+    #[error("no node named '{0}' exists")]
+    UnknownNode(String),
 }
 
 impl Node {
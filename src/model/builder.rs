@@ -0,0 +1,252 @@
+use super::{Header, Instruction, Node, OpCode, Operand, Program, Value};
+
+/// Builds a [Node] one instruction at a time.
+///
+/// Labels are placed at the position of the next instruction added after them, so jumps can
+/// refer to labels before or after they are defined.
+#[derive(Clone, Debug)]
+pub struct NodeBuilder {
+    node: Node,
+}
+
+impl NodeBuilder {
+    #[must_use]
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            node: Node {
+                name: name.into(),
+                ..Node::default()
+            },
+        }
+    }
+
+    #[must_use]
+    pub fn tag<S: Into<String>>(mut self, tag: S) -> Self {
+        self.node.tags.push(tag.into());
+        self
+    }
+
+    #[must_use]
+    pub fn header<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.node.headers.push(Header {
+            key: key.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    /// Mark the position of the next instruction with the label [name].
+    #[must_use]
+    pub fn label<S: Into<String>>(mut self, name: S) -> Self {
+        let offset = self.node.instructions.len() as i32;
+        self.node.labels.insert(name.into(), offset);
+        self
+    }
+
+    /// Add an instruction with the given [opcode] and [operands].
+    #[must_use]
+    pub fn instruction(mut self, opcode: OpCode, operands: Vec<Value>) -> Self {
+        self.node.instructions.push(Instruction {
+            opcode: opcode as i32,
+            operands: operands.into_iter().map(Operand::from).collect(),
+        });
+        self
+    }
+
+    #[must_use]
+    pub fn jump_to<S: Into<String>>(self, label: S) -> Self {
+        self.instruction(OpCode::JumpTo, vec![Value::StringValue(label.into())])
+    }
+
+    /// Jump to the label named by the value on top of the stack.
+    #[must_use]
+    pub fn jump(self) -> Self {
+        self.instruction(OpCode::Jump, vec![])
+    }
+
+    /// Show the line identified by [key], substituting the top [substitutions] values of the
+    /// stack into it.
+    #[must_use]
+    pub fn line<S: Into<String>>(self, key: S, substitutions: usize) -> Self {
+        self.instruction(
+            OpCode::RunLine,
+            vec![
+                Value::StringValue(key.into()),
+                Value::FloatValue(substitutions as f32),
+            ],
+        )
+    }
+
+    #[must_use]
+    pub fn command<S: Into<String>>(self, text: S, substitutions: usize) -> Self {
+        self.instruction(
+            OpCode::RunCommand,
+            vec![
+                Value::StringValue(text.into()),
+                Value::FloatValue(substitutions as f32),
+            ],
+        )
+    }
+
+    /// Offer an option with the text of the line [key], leading to the label [target].
+    #[must_use]
+    pub fn option<K: Into<String>, T: Into<String>>(
+        self,
+        key: K,
+        target: T,
+        substitutions: usize,
+    ) -> Self {
+        self.add_option(key.into(), target.into(), substitutions, false)
+    }
+
+    /// Offer an option that is only enabled if the value below its substitutions on the stack
+    /// is `true`.
+    #[must_use]
+    pub fn conditional_option<K: Into<String>, T: Into<String>>(
+        self,
+        key: K,
+        target: T,
+        substitutions: usize,
+    ) -> Self {
+        self.add_option(key.into(), target.into(), substitutions, true)
+    }
+
+    fn add_option(
+        self,
+        key: String,
+        target: String,
+        substitutions: usize,
+        condition: bool,
+    ) -> Self {
+        self.instruction(
+            OpCode::AddOption,
+            vec![
+                Value::StringValue(key),
+                Value::StringValue(target),
+                Value::FloatValue(substitutions as f32),
+                Value::BoolValue(condition),
+            ],
+        )
+    }
+
+    #[must_use]
+    pub fn show_options(self) -> Self {
+        self.instruction(OpCode::ShowOptions, vec![])
+    }
+
+    #[must_use]
+    pub fn push_string<S: Into<String>>(self, value: S) -> Self {
+        self.instruction(OpCode::PushString, vec![Value::StringValue(value.into())])
+    }
+
+    #[must_use]
+    pub fn push_float(self, value: f32) -> Self {
+        self.instruction(OpCode::PushFloat, vec![Value::FloatValue(value)])
+    }
+
+    #[must_use]
+    pub fn push_bool(self, value: bool) -> Self {
+        self.instruction(OpCode::PushBool, vec![Value::BoolValue(value)])
+    }
+
+    #[must_use]
+    pub fn push_null(self) -> Self {
+        self.instruction(OpCode::PushNull, vec![])
+    }
+
+    #[must_use]
+    pub fn jump_if_false<S: Into<String>>(self, label: S) -> Self {
+        self.instruction(OpCode::JumpIfFalse, vec![Value::StringValue(label.into())])
+    }
+
+    #[must_use]
+    pub fn pop(self) -> Self {
+        self.instruction(OpCode::Pop, vec![])
+    }
+
+    /// Call the function named [function] with the top [argc] values of the stack, pushing the
+    /// argument count first as compiled programs do.
+    #[must_use]
+    pub fn call<S: Into<String>>(self, function: S, argc: usize) -> Self {
+        self.push_float(argc as f32)
+            .instruction(OpCode::CallFunc, vec![Value::StringValue(function.into())])
+    }
+
+    #[must_use]
+    pub fn push_variable<S: Into<String>>(self, name: S) -> Self {
+        self.instruction(OpCode::PushVariable, vec![Value::StringValue(name.into())])
+    }
+
+    /// Store the value on top of the stack in the variable [name], leaving it on the stack.
+    #[must_use]
+    pub fn store_variable<S: Into<String>>(self, name: S) -> Self {
+        self.instruction(OpCode::StoreVariable, vec![Value::StringValue(name.into())])
+    }
+
+    #[must_use]
+    pub fn stop(self) -> Self {
+        self.instruction(OpCode::Stop, vec![])
+    }
+
+    /// Run the node named [name].
+    #[must_use]
+    pub fn run_node<S: Into<String>>(self, name: S) -> Self {
+        self.push_string(name).instruction(OpCode::RunNode, vec![])
+    }
+
+    #[must_use]
+    pub fn build(self) -> Node {
+        self.node
+    }
+}
+
+impl From<NodeBuilder> for Node {
+    fn from(builder: NodeBuilder) -> Self {
+        builder.build()
+    }
+}
+
+/// Builds a [Program] from nodes and initial variable values.
+#[derive(Clone, Debug, Default)]
+pub struct ProgramBuilder {
+    program: Program,
+}
+
+impl ProgramBuilder {
+    #[must_use]
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            program: Program {
+                name: name.into(),
+                ..Program::default()
+            },
+        }
+    }
+
+    /// Add [node] to the program, replacing any existing node with the same name.
+    #[must_use]
+    pub fn node<N: Into<Node>>(mut self, node: N) -> Self {
+        let node = node.into();
+        self.program.nodes.insert(node.name.clone(), node);
+        self
+    }
+
+    #[must_use]
+    pub fn initial_value<S: Into<String>, V: Into<Value>>(mut self, name: S, value: V) -> Self {
+        self.program
+            .initial_values
+            .insert(name.into(), Operand::from(value.into()));
+        self
+    }
+
+    #[must_use]
+    pub fn build(self) -> Program {
+        self.program
+    }
+}
+
+impl From<ProgramBuilder> for Program {
+    fn from(builder: ProgramBuilder) -> Self {
+        builder.build()
+    }
+}
@@ -1,15 +1,36 @@
+use std::any::Any;
+use std::cell::RefCell;
+
 use thiserror::Error;
 
+use crate::command::{CommandError, CommandRegistry};
 use crate::function::{CallContext, CallError, Library};
-use crate::model::{Instruction, Node, NodeError, OpCode, Operand, Operands, Value, ValueError};
-use crate::story::Story;
+use crate::model::{Instruction, NodeError, OpCode, Value, ValueError};
+use crate::story::{CompiledNode, Op, Story, Symbol, Target};
 use crate::variables::VariableStore;
 
+mod checkpoint;
+mod random;
+
+pub use checkpoint::{CheckpointError, SavedCheckpoint, CHECKPOINT_VERSION};
+pub use random::Rng;
+
 /// An event generated by stepping through multiple [Story] instructions that can
 /// inform the user on how the narrative is unfolding.
-#[derive(Debug, PartialEq, Eq)]
+///
+/// The story reaching a `Stop` instruction or the end of a node now takes two steps: the first
+/// produces [`StoryEvent::NodeComplete`] and the second [`StoryEvent::DialogueComplete`]. The
+/// deprecated [`StoryEvent::Started`] and [`StoryEvent::Complete`] are no longer produced, so
+/// loops that stopped on [`StoryEvent::Complete`] must stop on
+/// [`StoryEvent::DialogueComplete`] instead.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum StoryEvent {
+    #[deprecated(note = "never produced; use `StoryEvent::NodeStart` instead")]
     Started,
+    /// The story has entered the node named by [name].
+    NodeStart {
+        name: String,
+    },
     AddOption {
         enabled: bool,
         key: String,
@@ -22,44 +43,193 @@ pub enum StoryEvent {
         substitutions: Vec<String>,
     },
     Command(String),
+    /// The story has left the node named by [name], either by running another node or by
+    /// reaching the end of the dialogue.
+    NodeComplete {
+        name: String,
+    },
+    /// The dialogue has finished and there is nothing left to run. Stepping the returned
+    /// checkpoint again produces this event again.
+    DialogueComplete,
+    #[deprecated(note = "never produced; use `StoryEvent::DialogueComplete` instead")]
     Complete,
 }
 
+/// An event that a [`StoryCheckpoint`] will produce before any further instructions are run.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum PendingEvent {
+    NodeStart,
+    DialogueComplete,
+}
+
+/// An option offered by an [`StoryEvent::AddOption`] that may later be selected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct OfferedOption {
+    pub(crate) key: String,
+    pub(crate) substitutions: Vec<String>,
+    pub(crate) target: String,
+    pub(crate) enabled: bool,
+}
+
+/// The options offered since the last [`StoryEvent::ShowOptions`].
+#[derive(Clone, Debug, Default)]
+struct OptionSet {
+    offered: Vec<OfferedOption>,
+
+    /// Whether the options have been shown and the story is waiting for a selection.
+    shown: bool,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum OptionError {
+    #[error("the story is not waiting for an option to be selected")]
+    NotAwaitingSelection,
+
+    #[error("no option leading to '{0}' was offered")]
+    UnknownOption(String),
+
+    #[error("no option with index {0} was offered")]
+    InvalidIndex(usize),
+
+    #[error("the option leading to '{0}' is disabled")]
+    Disabled(String),
+}
+
 /// A [`StoryCheckpoint`] represents an addressable point in the [Story]. It can be saved
 /// and later resumed to reload
+///
+/// Checkpoints refer to the lowered form of a node held by their [Story], so they can no
+/// longer be created from a [`Node`](crate::model::Node) with `StoryCheckpoint::new`. Use
+/// [`Story::checkpoint_at`] to start at a node instead.
 #[derive(Clone)]
 pub struct StoryCheckpoint<'r> {
     /// The node the story checkpoint was created at, if any. If none, the [StoryRunner] will
     /// follow the starting node.
-    node: &'r Node,
+    node: &'r CompiledNode,
 
     /// Offset of the next instruction to execute within the node.
     node_instruction_offset: usize,
 
     /// The evaluation stack at the time of this checkpoint.
     stack: EvaluationStack,
+
+    /// An event to emit before resuming execution, if any.
+    pending: Option<PendingEvent>,
+
+    /// The options offered to the player in the current option set.
+    options: OptionSet,
+
+    /// The state of the random number generator available to functions.
+    rng: Rng,
 }
 
 impl<'r> StoryCheckpoint<'r> {
-    const fn at(node: &'r Node, pc: usize, stack: EvaluationStack) -> Self {
+    const fn at(node: &'r CompiledNode, pc: usize, stack: EvaluationStack, rng: Rng) -> Self {
         Self {
             node,
             node_instruction_offset: pc,
             stack,
+            pending: None,
+            options: OptionSet {
+                offered: vec![],
+                shown: false,
+            },
+            rng,
         }
     }
 
+    const fn with_pending(mut self, pending: PendingEvent) -> Self {
+        self.pending = Some(pending);
+        self
+    }
+
+    pub(crate) fn new(node: &'r CompiledNode) -> Self {
+        Self::at(node, 0, EvaluationStack(vec![]), Rng::from_entropy())
+            .with_pending(PendingEvent::NodeStart)
+    }
+
+    /// Seed the random number generator used by functions such as `random` and `dice` when
+    /// running from this checkpoint.
+    ///
+    /// Checkpoints created with [`Story::checkpoint_at`] are seeded differently on each run, so
+    /// that each playthrough rolls differently. Seed them explicitly to replay the same rolls.
     #[must_use]
-    pub const fn new(node: &'r Node) -> StoryCheckpoint {
-        Self {
-            node,
-            node_instruction_offset: 0,
-            stack: EvaluationStack(vec![]),
+    pub const fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = Rng::new(seed);
+        self
+    }
+
+    /// Whether the story has shown a set of options and is waiting for one to be selected.
+    #[must_use]
+    pub const fn is_awaiting_selection(&self) -> bool {
+        self.options.shown
+    }
+
+    /// The options offered since the last set of options was shown, in the order they were
+    /// offered.
+    pub(crate) fn offered_options(&self) -> &[OfferedOption] {
+        &self.options.offered
+    }
+
+    /// Select the option leading to the label named by [target] from the options that were
+    /// most recently shown.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the story is not waiting for a selection, or no enabled option leading
+    /// to [target] was offered.
+    pub fn select_option<S>(&mut self, target: S) -> Result<(), OptionError>
+    where
+        S: AsRef<str>,
+    {
+        if !self.options.shown {
+            return Err(OptionError::NotAwaitingSelection);
+        }
+
+        let target = target.as_ref();
+        let offered = &self.options.offered;
+        let index = offered
+            .iter()
+            .position(|option| option.target == target && option.enabled)
+            .or_else(|| offered.iter().position(|option| option.target == target))
+            .ok_or_else(|| OptionError::UnknownOption(target.to_string()))?;
+
+        self.select_option_at(index)
+    }
+
+    /// Select the option at [index] within the options that were most recently shown, counting
+    /// from zero in the order they were offered.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the story is not waiting for a selection, or the option at [index] does
+    /// not exist or is disabled.
+    pub fn select_option_at(&mut self, index: usize) -> Result<(), OptionError> {
+        if !self.options.shown {
+            return Err(OptionError::NotAwaitingSelection);
+        }
+
+        let option = self
+            .options
+            .offered
+            .get(index)
+            .ok_or(OptionError::InvalidIndex(index))?;
+
+        if !option.enabled {
+            return Err(OptionError::Disabled(option.target.clone()));
         }
+
+        self.stack.push(option.target.clone());
+        self.options = OptionSet::default();
+
+        Ok(())
     }
 
-    pub fn select_option(&mut self, name: String) {
-        self.stack.push(name);
+    /// Create an owned copy of this checkpoint that can be persisted and later restored with
+    /// [`Story::restore`].
+    #[must_use]
+    pub fn save(&self) -> SavedCheckpoint {
+        SavedCheckpoint::from(self)
     }
 }
 
@@ -79,7 +249,7 @@ impl EvaluationStack {
     ///
     /// See [`pop_any`]
     pub fn peek_any(&mut self) -> Result<Value, ValueError> {
-        self.0.get(0).cloned().ok_or(ValueError::Missing)
+        self.0.last().cloned().ok_or(ValueError::Missing)
     }
 
     /// # Errors
@@ -90,7 +260,7 @@ impl EvaluationStack {
         T: TryFrom<Value, Error = ValueError>,
     {
         self.0
-            .get(0)
+            .last()
             .ok_or(ValueError::Missing)
             .and_then(|v| T::try_from(v.clone()))
     }
@@ -127,6 +297,22 @@ pub struct StoryRunnerError {
     instruction: Instruction,
 }
 
+impl StoryRunnerError {
+    fn new(source: InstructionError, node: &CompiledNode, pc: usize) -> Self {
+        Self {
+            source,
+            node: node.name().to_string(),
+            pc,
+            instruction: node
+                .source
+                .instructions
+                .get(pc)
+                .cloned()
+                .unwrap_or_default(),
+        }
+    }
+}
+
 /// An error that occurred during evaluation of a [Story].
 #[derive(Error, Debug)]
 pub enum InstructionError {
@@ -145,155 +331,243 @@ pub enum InstructionError {
 
     #[error(transparent)]
     Evaluation(#[from] ValueError),
+
+    #[error(transparent)]
+    Command(#[from] CommandError),
+
+    #[error("the story is waiting for an option to be selected")]
+    AwaitingSelection,
+
+    #[error("instruction budget of {0} exhausted before an event was produced")]
+    BudgetExhausted(usize),
+}
+
+/// The result of advancing a [Story] with [`StoryRunner::step_with_budget`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// The story produced an event.
+    Event(StoryEvent),
+    /// The instruction budget ran out before an event was produced. Stepping again from the
+    /// returned checkpoint continues where execution left off.
+    Yielded,
+}
+
+/// What a [`StoryRunner`] does when the instruction budget given to
+/// [`StoryRunner::step_with_budget`] runs out.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum BudgetExhaustion {
+    /// Return [`StepOutcome::Yielded`] with a resumable checkpoint.
+    #[default]
+    Yield,
+    /// Fail with [`InstructionError::BudgetExhausted`].
+    Error,
 }
 
 enum ControlFlow<'a> {
     Next,
-    Jump(&'a Node, usize),
+    Jump(usize),
+    /// Exit the current node and start running the given node.
+    Run(&'a CompiledNode),
+    /// Exit the current node and end the dialogue.
+    Stop,
 }
 
 /// Driver for running and evaluating a [Story].
 #[derive(Default)]
 pub struct StoryRunner {
     library: Library,
+    commands: CommandRegistry,
+    budget_exhaustion: BudgetExhaustion,
+    functions: RefCell<FunctionCache>,
+}
+
+/// The functions of a [Library] called by the story most recently run, resolved once per name
+/// rather than on every call.
+#[derive(Default)]
+struct FunctionCache {
+    /// The id of the story the cache was filled for.
+    story: Option<u64>,
+
+    /// The index of each function within the library by the symbol of its name, if it has been
+    /// resolved, and `Some(None)` if the library has no function with that name.
+    indices: Vec<Option<Option<usize>>>,
 }
 
 impl StoryRunner {
     #[must_use]
-    pub const fn new(library: Library) -> Self {
-        Self { library }
+    pub fn new(library: Library) -> Self {
+        Self {
+            library,
+            commands: CommandRegistry::new(),
+            budget_exhaustion: BudgetExhaustion::Yield,
+            functions: RefCell::default(),
+        }
+    }
+
+    /// Dispatch commands registered in [commands] when the story runs them, instead of
+    /// emitting [`StoryEvent::Command`].
+    #[must_use]
+    pub fn with_commands(mut self, commands: CommandRegistry) -> Self {
+        self.commands = commands;
+        self
+    }
+
+    /// Configure what happens when the budget given to [`StoryRunner::step_with_budget`] runs
+    /// out.
+    #[must_use]
+    pub fn with_budget_exhaustion(mut self, budget_exhaustion: BudgetExhaustion) -> Self {
+        self.budget_exhaustion = budget_exhaustion;
+        self
+    }
+
+    /// Get the index in the library of the function named by [name] in [story].
+    fn function(&self, story: &Story, name: Symbol) -> Option<usize> {
+        let mut cache = self.functions.borrow_mut();
+
+        if cache.story != Some(story.id()) {
+            cache.story = Some(story.id());
+            cache.indices = vec![None; story.string_count()];
+        }
+
+        *cache.indices[name.index()].get_or_insert_with(|| self.library.index(story.string(name)))
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn execute<'s, V>(
-        &'s self,
+        &self,
         story: &'s Story,
-        node: &'s Node,
-        opcode: OpCode,
-        operands: &'s Vec<Operand>,
+        node: &'s CompiledNode,
+        op: Op,
         stack: &mut EvaluationStack,
+        rng: &mut Rng,
         variables: &mut V,
-    ) -> Result<(ControlFlow, Option<StoryEvent>), InstructionError>
+        user_data: &mut dyn Any,
+    ) -> Result<(ControlFlow<'s>, Option<StoryEvent>), InstructionError>
     where
         V: VariableStore,
     {
-        return match opcode {
-            OpCode::JumpTo => {
-                let label_name = operands.at::<String>(0)?;
-                let label_offset = node.resolve_label(&label_name)?;
-
-                Ok((ControlFlow::Jump(node, label_offset), None))
+        fn pop_substitutions(
+            stack: &mut EvaluationStack,
+            count: usize,
+        ) -> Result<Vec<String>, ValueError> {
+            let mut substitutions = Vec::with_capacity(count);
+
+            for _ in 0..count {
+                substitutions.push(stack.pop::<String>()?);
             }
-            OpCode::Jump => {
-                let label_name = stack.pop::<String>()?;
-                let label_offset = node.resolve_label(&label_name)?;
 
-                Ok((ControlFlow::Jump(node, label_offset), None))
-            }
-            OpCode::RunLine => {
-                let key = operands.at::<String>(0)?;
+            substitutions.reverse();
+            Ok(substitutions)
+        }
 
-                let substitutions = if operands.len() > 1 {
-                    let expression_count = operands.at::<f32>(1)? as usize;
-                    let mut substitutions = Vec::with_capacity(expression_count);
+        fn resolve(story: &Story, target: Target) -> Result<usize, NodeError> {
+            match target {
+                Target::Offset(offset) => Ok(offset),
+                Target::Unresolved(label) => {
+                    Err(NodeError::InvalidLabel(story.string(label).to_string()))
+                }
+            }
+        }
 
-                    for _ in 0..expression_count {
-                        substitutions.push(stack.pop::<String>()?);
-                    }
+        match op {
+            Op::JumpTo(target) => Ok((ControlFlow::Jump(resolve(story, target)?), None)),
+            Op::Jump => {
+                let label_name = stack.pop::<String>()?;
+                let label_offset = node
+                    .label(&label_name)
+                    .ok_or(NodeError::InvalidLabel(label_name))?;
 
-                    substitutions.reverse();
-                    substitutions
-                } else {
-                    vec![]
-                };
+                Ok((ControlFlow::Jump(label_offset), None))
+            }
+            Op::RunLine { key, substitutions } => {
+                let substitutions = pop_substitutions(stack, substitutions)?;
 
                 Ok((
                     ControlFlow::Next,
-                    Some(StoryEvent::ShowLine { key, substitutions }),
+                    Some(StoryEvent::ShowLine {
+                        key: story.string(key).to_string(),
+                        substitutions,
+                    }),
                 ))
             }
-            OpCode::RunCommand => {
-                let mut command_text = operands.at::<String>(0)?;
+            Op::RunCommand {
+                text,
+                substitutions,
+            } => {
+                let mut command_text = story.string(text).to_string();
 
-                if operands.len() > 1 {
-                    let expression_count = operands.at::<f32>(1)? as usize;
+                for index in (0..substitutions).rev() {
+                    let substitution: String = stack.pop()?;
+                    let search = format!("{{{}}}", index);
 
-                    for index in (0..expression_count).rev() {
-                        let substitution: String = stack.pop()?;
-                        let search = format!("{{{}}}", index);
+                    command_text = command_text.replace(&search, &substitution);
+                }
+
+                let cx = CallContext {
+                    node: &node.source,
+                    story,
+                    variables,
+                    rng,
+                    user_data: &mut *user_data,
+                };
 
-                        command_text = command_text.replace(&search, &substitution);
-                    }
+                if self.commands.dispatch(cx, &command_text)? {
+                    return Ok((ControlFlow::Next, None));
                 }
 
                 Ok((ControlFlow::Next, Some(StoryEvent::Command(command_text))))
             }
-            OpCode::AddOption => {
-                let key = operands.at::<String>(0)?;
-                let target = operands.at::<String>(1)?;
-
-                let substitutions = if operands.len() > 2 {
-                    let expression_count = operands.at::<f32>(2)? as usize;
-                    let mut substitutions = Vec::with_capacity(expression_count);
-
-                    for _ in 0..expression_count {
-                        substitutions.push(stack.pop::<String>()?);
-                    }
-
-                    substitutions.reverse();
-                    substitutions
-                } else {
-                    vec![]
-                };
-
-                let enabled = if operands.len() > 3 && operands.at::<bool>(3)? {
-                    stack.pop()?
-                } else {
-                    true
-                };
+            Op::AddOption {
+                key,
+                target,
+                substitutions,
+                has_condition,
+            } => {
+                let substitutions = pop_substitutions(stack, substitutions)?;
+                let enabled = if has_condition { stack.pop()? } else { true };
 
                 Ok((
                     ControlFlow::Next,
                     Some(StoryEvent::AddOption {
                         enabled,
-                        key,
+                        key: story.string(key).to_string(),
                         substitutions,
-                        target,
+                        target: story.string(target).to_string(),
                     }),
                 ))
             }
-            OpCode::ShowOptions => Ok((ControlFlow::Next, Some(StoryEvent::ShowOptions))),
-            OpCode::PushString => {
-                stack.push(operands.at::<String>(0)?);
+            Op::ShowOptions => Ok((ControlFlow::Next, Some(StoryEvent::ShowOptions))),
+            Op::PushString(value) => {
+                stack.push(story.string(value).to_string());
                 Ok((ControlFlow::Next, None))
             }
-            OpCode::PushFloat => {
-                stack.push(operands.at::<f32>(0)?);
+            Op::PushFloat(value) => {
+                stack.push(value);
                 Ok((ControlFlow::Next, None))
             }
-            OpCode::PushBool => {
-                stack.push(operands.at::<bool>(0)?);
+            Op::PushBool(value) => {
+                stack.push(value);
                 Ok((ControlFlow::Next, None))
             }
-            OpCode::PushNull => Err(InstructionError::UnsupportedInstruction(opcode)),
-            OpCode::JumpIfFalse => {
+            Op::PushNull => {
+                stack.push(Value::NullValue);
+                Ok((ControlFlow::Next, None))
+            }
+            Op::JumpIfFalse(target) => {
                 let condition = stack.peek::<bool>()?;
-                let flow = if !condition {
-                    let target_name = operands.at::<String>(0)?;
-                    let target = node.resolve_label(&target_name)?;
-
-                    ControlFlow::Jump(node, target)
-                } else {
+                let flow = if condition {
                     ControlFlow::Next
+                } else {
+                    ControlFlow::Jump(resolve(story, target)?)
                 };
 
                 Ok((flow, None))
             }
-            OpCode::Pop => {
+            Op::Pop => {
                 let _ = stack.pop_any()?;
                 Ok((ControlFlow::Next, None))
             }
-            OpCode::CallFunc => {
-                let name = operands.at::<String>(0)?;
+            Op::CallFunc(name) => {
                 let parameter_count = stack.pop::<f32>()? as usize;
                 let mut parameters = Vec::with_capacity(parameter_count);
 
@@ -304,21 +578,26 @@ impl StoryRunner {
                 parameters.reverse();
 
                 let cx = CallContext {
-                    node,
+                    node: &node.source,
                     story,
                     variables,
+                    rng,
+                    user_data: &mut *user_data,
                 };
 
-                let return_value = self.library.call(name, cx, parameters)?;
+                let index = self
+                    .function(story, name)
+                    .ok_or_else(|| CallError::UnknownFunction(story.string(name).to_string()))?;
+                let return_value = self.library.call_at(index, cx, parameters)?;
                 stack.push(return_value);
 
                 Ok((ControlFlow::Next, None))
             }
-            OpCode::PushVariable => {
-                let var_name = operands.at::<String>(0)?;
+            Op::PushVariable(name) => {
+                let var_name = story.string(name);
                 let var_value = variables
-                    .get(&var_name)
-                    .or_else(|| story.initial_value(&var_name));
+                    .get(var_name)
+                    .or_else(|| story.initial_value(var_name));
 
                 if let Some(value) = var_value {
                     stack.push(value.clone());
@@ -327,22 +606,40 @@ impl StoryRunner {
                     Err(InstructionError::Evaluation(ValueError::Missing))
                 }
             }
-            OpCode::StoreVariable => {
+            Op::StoreVariable(name) => {
                 let value = stack.peek_any()?;
-                let var_name = operands.at::<String>(0)?;
-
-                variables.set(&var_name, value);
+                variables.set(story.string(name), value);
 
                 Ok((ControlFlow::Next, None))
             }
-            OpCode::Stop => Ok((ControlFlow::Next, Some(StoryEvent::Complete))),
-            OpCode::RunNode => {
+            Op::Stop => Ok((ControlFlow::Stop, None)),
+            Op::RunNode(Some(index)) => Ok((ControlFlow::Run(story.compiled_node_at(index)), None)),
+            Op::RunNode(None) => {
                 let node_name = stack.pop::<String>()?;
-                let new_node = story.node(node_name).unwrap();
+                let new_node = story
+                    .compiled_node(&node_name)
+                    .ok_or(NodeError::UnknownNode(node_name))?;
 
-                Ok((ControlFlow::Jump(new_node, 0), None))
+                Ok((ControlFlow::Run(new_node), None))
             }
+        }
+    }
+
+    /// Record a visit to [node] and produce the event signalling that it has completed,
+    /// continuing from [checkpoint] afterwards.
+    fn exit_node<'a, V: VariableStore>(
+        story: &'a Story,
+        node: &'a CompiledNode,
+        checkpoint: StoryCheckpoint<'a>,
+        variables: &mut V,
+    ) -> (StoryCheckpoint<'a>, StepOutcome) {
+        story.record_visit(variables, node);
+
+        let event = StoryEvent::NodeComplete {
+            name: node.name().to_string(),
         };
+
+        (checkpoint, StepOutcome::Event(event))
     }
 
     /// Advance the story forward from the given [checkpoint].
@@ -352,45 +649,192 @@ impl StoryRunner {
     /// Will return `Err` if could not be advanced due to an error decoding or evaluating
     /// instructions.
     pub fn step<'a, V: VariableStore>(
-        &'a self,
+        &self,
+        story: &'a Story,
+        checkpoint: StoryCheckpoint<'a>,
+        variables: &mut V,
+    ) -> Result<(StoryCheckpoint<'a>, StoryEvent), StoryRunnerError> {
+        self.step_with_user_data(story, checkpoint, variables, &mut ())
+    }
+
+    /// Advance the story forward from the given [checkpoint], making [`user_data`] available to
+    /// functions and commands through [`CallContext::user_data_mut`].
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if could not be advanced due to an error decoding or evaluating
+    /// instructions.
+    pub fn step_with_user_data<'a, V: VariableStore>(
+        &self,
+        story: &'a Story,
+        checkpoint: StoryCheckpoint<'a>,
+        variables: &mut V,
+        user_data: &mut dyn Any,
+    ) -> Result<(StoryCheckpoint<'a>, StoryEvent), StoryRunnerError> {
+        let mut checkpoint = checkpoint;
+        loop {
+            match self.step_with_budget_and_user_data(
+                story,
+                checkpoint,
+                variables,
+                usize::MAX,
+                user_data,
+            )? {
+                (checkpoint, StepOutcome::Event(event)) => return Ok((checkpoint, event)),
+                (next, StepOutcome::Yielded) => checkpoint = next,
+            }
+        }
+    }
+
+    /// Advance the story forward from the given [checkpoint], executing at most
+    /// [`max_instructions`] instructions.
+    ///
+    /// If the budget runs out before an event is produced, the behaviour configured with
+    /// [`StoryRunner::with_budget_exhaustion`] applies. By default a [`StepOutcome::Yielded`] is
+    /// returned alongside a checkpoint that execution can be resumed from.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if could not be advanced due to an error decoding or evaluating
+    /// instructions, or if the budget ran out and [`BudgetExhaustion::Error`] is configured.
+    pub fn step_with_budget<'a, V: VariableStore>(
+        &self,
         story: &'a Story,
         checkpoint: StoryCheckpoint<'a>,
         variables: &mut V,
-    ) -> Result<(StoryCheckpoint, StoryEvent), StoryRunnerError> {
+        max_instructions: usize,
+    ) -> Result<(StoryCheckpoint<'a>, StepOutcome), StoryRunnerError> {
+        self.step_with_budget_and_user_data(story, checkpoint, variables, max_instructions, &mut ())
+    }
+
+    /// Advance the story forward from the given [checkpoint] as with
+    /// [`StoryRunner::step_with_budget`], making [`user_data`] available to functions and
+    /// commands through [`CallContext::user_data_mut`].
+    ///
+    /// # Errors
+    ///
+    /// See [`StoryRunner::step_with_budget`].
+    pub fn step_with_budget_and_user_data<'a, V: VariableStore>(
+        &self,
+        story: &'a Story,
+        checkpoint: StoryCheckpoint<'a>,
+        variables: &mut V,
+        max_instructions: usize,
+        user_data: &mut dyn Any,
+    ) -> Result<(StoryCheckpoint<'a>, StepOutcome), StoryRunnerError> {
         let StoryCheckpoint {
-            mut node,
+            node,
             node_instruction_offset: mut pc,
             mut stack,
+            pending,
+            mut options,
+            mut rng,
         } = checkpoint;
 
+        match pending {
+            Some(PendingEvent::NodeStart) => {
+                let event = StoryEvent::NodeStart {
+                    name: node.name().to_string(),
+                };
+
+                return Ok((
+                    StoryCheckpoint::at(node, pc, stack, rng),
+                    StepOutcome::Event(event),
+                ));
+            }
+            Some(PendingEvent::DialogueComplete) => {
+                // Completion is terminal, so the event stays pending and every later step
+                // reports it again without running anything.
+                return Ok((
+                    StoryCheckpoint::at(node, pc, stack, rng)
+                        .with_pending(PendingEvent::DialogueComplete),
+                    StepOutcome::Event(StoryEvent::DialogueComplete),
+                ));
+            }
+            None => {}
+        }
+
+        if options.shown {
+            return Err(StoryRunnerError::new(
+                InstructionError::AwaitingSelection,
+                node,
+                pc,
+            ));
+        }
+
+        let mut executed = 0;
         loop {
-            let instruction = &node.instructions[pc];
-            let operands = &instruction.operands;
-            let step = OpCode::from_i32(instruction.opcode)
-                .ok_or(InstructionError::InvalidInstruction(instruction.opcode))
-                .and_then(|opcode| {
-                    self.execute(story, node, opcode, operands, &mut stack, variables)
-                });
-
-            let (flow, event) = match step {
-                Ok((flow, event)) => (flow, event),
-                Err(source) => {
-                    return Err(StoryRunnerError {
-                        source,
-                        node: node.name.clone(),
+            if executed >= max_instructions {
+                if self.budget_exhaustion == BudgetExhaustion::Error {
+                    return Err(StoryRunnerError::new(
+                        InstructionError::BudgetExhausted(executed),
+                        node,
                         pc,
-                        instruction: instruction.clone(),
-                    });
+                    ));
                 }
+
+                let checkpoint = StoryCheckpoint {
+                    options,
+                    ..StoryCheckpoint::at(node, pc, stack, rng)
+                };
+
+                return Ok((checkpoint, StepOutcome::Yielded));
+            }
+
+            executed += 1;
+
+            let Some(op) = node.ops.get(pc) else {
+                // Running off the end of a node completes it, as if it ended with a `Stop`.
+                let checkpoint = StoryCheckpoint::at(node, pc, stack, rng)
+                    .with_pending(PendingEvent::DialogueComplete);
+
+                return Ok(Self::exit_node(story, node, checkpoint, variables));
             };
 
-            (node, pc) = match flow {
-                ControlFlow::Next => (node, pc + 1),
-                ControlFlow::Jump(node, target) => (node, target),
+            let (flow, event) = self
+                .execute(story, node, *op, &mut stack, &mut rng, variables, user_data)
+                .map_err(|source| StoryRunnerError::new(source, node, pc))?;
+
+            pc = match flow {
+                ControlFlow::Next => pc + 1,
+                ControlFlow::Jump(target) => target,
+                ControlFlow::Run(next) => {
+                    let checkpoint = StoryCheckpoint::at(next, 0, stack, rng)
+                        .with_pending(PendingEvent::NodeStart);
+
+                    return Ok(Self::exit_node(story, node, checkpoint, variables));
+                }
+                ControlFlow::Stop => {
+                    let checkpoint = StoryCheckpoint::at(node, pc, stack, rng)
+                        .with_pending(PendingEvent::DialogueComplete);
+
+                    return Ok(Self::exit_node(story, node, checkpoint, variables));
+                }
             };
 
+            match &event {
+                Some(StoryEvent::AddOption {
+                    enabled,
+                    key,
+                    substitutions,
+                    target,
+                }) => options.offered.push(OfferedOption {
+                    key: key.clone(),
+                    substitutions: substitutions.clone(),
+                    target: target.clone(),
+                    enabled: *enabled,
+                }),
+                Some(StoryEvent::ShowOptions) => options.shown = true,
+                _ => {}
+            }
+
             if let Some(event) = event {
-                return Ok((StoryCheckpoint::at(node, pc, stack), event));
+                let checkpoint = StoryCheckpoint {
+                    options,
+                    ..StoryCheckpoint::at(node, pc, stack, rng)
+                };
+
+                return Ok((checkpoint, StepOutcome::Event(event)));
             }
         }
     }
@@ -0,0 +1,205 @@
+use prost::{DecodeError, Message};
+use thiserror::Error;
+
+use super::{EvaluationStack, OfferedOption, OptionSet, PendingEvent, Rng, StoryCheckpoint};
+use crate::model::{Operand, Value};
+use crate::story::Story;
+
+/// The version of the [`SavedCheckpoint`] encoding produced by this build.
+///
+/// Fields are only ever added to the encoding under new tags, and a checkpoint saved before a
+/// field existed decodes it as empty: no pending event, offered options without their line
+/// IDs, and a random number generator that is seeded afresh, as for a new checkpoint. Such
+/// additions deliberately keep the version, so that older saves remain loadable. The version
+/// is bumped only when the meaning of an existing field changes or a field is removed.
+pub const CHECKPOINT_VERSION: u32 = 1;
+
+/// An owned, serializable form of a [`StoryCheckpoint`] that does not borrow from the [Story]
+/// it was created in.
+///
+/// Saved checkpoints identify their position by node name and instruction offset, so they can
+/// be written to disk and later restored with [`Story::restore`].
+#[derive(Clone, PartialEq, Message)]
+pub struct SavedCheckpoint {
+    #[prost(uint32, tag = "1")]
+    version: u32,
+
+    #[prost(string, tag = "2")]
+    node: String,
+
+    #[prost(uint64, tag = "3")]
+    pc: u64,
+
+    #[prost(message, repeated, tag = "4")]
+    stack: Vec<Operand>,
+
+    #[prost(uint32, tag = "5")]
+    pending: u32,
+
+    #[prost(message, repeated, tag = "6")]
+    options: Vec<SavedOption>,
+
+    #[prost(bool, tag = "7")]
+    options_shown: bool,
+
+    #[prost(uint64, optional, tag = "8")]
+    rng_state: Option<u64>,
+}
+
+/// An option that had been offered when a [`SavedCheckpoint`] was created.
+#[derive(Clone, PartialEq, Message)]
+struct SavedOption {
+    #[prost(string, tag = "1")]
+    target: String,
+
+    #[prost(bool, tag = "2")]
+    enabled: bool,
+
+    #[prost(string, tag = "3")]
+    key: String,
+
+    #[prost(string, repeated, tag = "4")]
+    substitutions: Vec<String>,
+}
+
+impl PendingEvent {
+    const fn encode(pending: Option<Self>) -> u32 {
+        match pending {
+            None => 0,
+            Some(Self::NodeStart) => 1,
+            Some(Self::DialogueComplete) => 2,
+        }
+    }
+
+    const fn decode(value: u32) -> Result<Option<Self>, CheckpointError> {
+        match value {
+            0 => Ok(None),
+            1 => Ok(Some(Self::NodeStart)),
+            2 => Ok(Some(Self::DialogueComplete)),
+            _ => Err(CheckpointError::InvalidPendingEvent(value)),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CheckpointError {
+    #[error("failed to decode saved checkpoint")]
+    Decode(#[from] DecodeError),
+
+    #[error("unsupported checkpoint version {0}, expected {CHECKPOINT_VERSION}")]
+    UnsupportedVersion(u32),
+
+    #[error("saved checkpoint refers to node '{0}' which no longer exists")]
+    UnknownNode(String),
+
+    #[error("saved checkpoint offset {1} is out of bounds for node '{0}'")]
+    InvalidOffset(String, usize),
+
+    #[error("saved checkpoint has an unrecognised pending event {0}")]
+    InvalidPendingEvent(u32),
+}
+
+impl SavedCheckpoint {
+    /// The name of the node this checkpoint was saved in.
+    #[must_use]
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    /// The offset of the next instruction to execute within [`SavedCheckpoint::node`].
+    #[must_use]
+    pub fn pc(&self) -> usize {
+        self.pc as usize
+    }
+
+    /// Encode this checkpoint into its binary representation.
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.encode_to_vec()
+    }
+
+    /// Decode a checkpoint previously produced by [`SavedCheckpoint::to_bytes`].
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the data is malformed or was written by an unsupported version.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, CheckpointError> {
+        let checkpoint = Self::decode(data)?;
+
+        if checkpoint.version != CHECKPOINT_VERSION {
+            return Err(CheckpointError::UnsupportedVersion(checkpoint.version));
+        }
+
+        Ok(checkpoint)
+    }
+
+    /// Resolve this checkpoint against the nodes of [story].
+    pub(crate) fn resolve<'s>(
+        &self,
+        story: &'s Story,
+    ) -> Result<StoryCheckpoint<'s>, CheckpointError> {
+        let node = story
+            .compiled_node(&self.node)
+            .ok_or_else(|| CheckpointError::UnknownNode(self.node.clone()))?;
+
+        let pc = self.pc();
+        if pc > node.ops.len() {
+            return Err(CheckpointError::InvalidOffset(self.node.clone(), pc));
+        }
+
+        let stack = self.stack.iter().map(Value::from).collect();
+
+        Ok(StoryCheckpoint {
+            pending: PendingEvent::decode(self.pending)?,
+            options: OptionSet {
+                offered: self
+                    .options
+                    .iter()
+                    .map(|option| OfferedOption {
+                        key: option.key.clone(),
+                        substitutions: option.substitutions.clone(),
+                        target: option.target.clone(),
+                        enabled: option.enabled,
+                    })
+                    .collect(),
+                shown: self.options_shown,
+            },
+            ..StoryCheckpoint::at(
+                node,
+                pc,
+                EvaluationStack(stack),
+                self.rng_state.map_or_else(Rng::from_entropy, Rng::new),
+            )
+        })
+    }
+}
+
+impl<'r> From<&StoryCheckpoint<'r>> for SavedCheckpoint {
+    fn from(checkpoint: &StoryCheckpoint<'r>) -> Self {
+        Self {
+            version: CHECKPOINT_VERSION,
+            node: checkpoint.node.name().to_string(),
+            pc: checkpoint.node_instruction_offset as u64,
+            stack: checkpoint
+                .stack
+                .0
+                .iter()
+                .map(|value| Operand::from(value.clone()))
+                .collect(),
+            pending: PendingEvent::encode(checkpoint.pending),
+            options: checkpoint
+                .options
+                .offered
+                .iter()
+                .map(|option| SavedOption {
+                    key: option.key.clone(),
+                    substitutions: option.substitutions.clone(),
+                    target: option.target.clone(),
+                    enabled: option.enabled,
+                })
+                .collect(),
+            options_shown: checkpoint.options.shown,
+            rng_state: Some(checkpoint.rng.state()),
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A small, seedable pseudo-random number generator used by the `random`, `random_range` and
+/// `dice` functions.
+///
+/// The generator is a `SplitMix64` sequence, so its entire state is a single [u64] that is
+/// carried by each [`StoryCheckpoint`](super::StoryCheckpoint) and saved alongside it. Replaying
+/// a story from the same checkpoint produces the same rolls.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    #[must_use]
+    pub const fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Create a generator with a seed that differs between runs, as used by checkpoints that
+    /// have not been given one with
+    /// [`StoryCheckpoint::with_seed`](super::StoryCheckpoint::with_seed).
+    #[must_use]
+    pub fn from_entropy() -> Self {
+        let mut hasher = RandomState::new().build_hasher();
+        if let Ok(time) = SystemTime::now().duration_since(UNIX_EPOCH) {
+            hasher.write_u128(time.as_nanos());
+        }
+
+        Self::new(hasher.finish())
+    }
+
+    /// The current state of the generator, which can be passed to [`Rng::new`] to continue the
+    /// same sequence.
+    #[must_use]
+    pub const fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Generate a number in the range `[0, 1)`.
+    pub fn next_f32(&mut self) -> f32 {
+        // The top 24 bits fill the mantissa of an f32 exactly.
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Generate an integer between [min] and [max], inclusive of both. The bounds may be given
+    /// in either order.
+    pub fn range(&mut self, min: i64, max: i64) -> i64 {
+        let (min, max) = if min <= max { (min, max) } else { (max, min) };
+        let span = max.abs_diff(min).wrapping_add(1);
+
+        if span == 0 {
+            // The range covers every i64.
+            return self.next_u64() as i64;
+        }
+
+        min.wrapping_add((self.next_u64() % span) as i64)
+    }
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self::from_entropy()
+    }
+}
@@ -0,0 +1,341 @@
+use std::any::Any;
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::line::ResolvedLine;
+use crate::markup::{parse_localized, MarkupError, MarkupParseResult};
+use crate::model::Value;
+use crate::runner::{OptionError, StoryCheckpoint, StoryEvent, StoryRunner, StoryRunnerError};
+use crate::story::Story;
+use crate::variables::VariableStore;
+
+/// An option presented to the player as part of an [`DialogueEvent::Options`] set.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DialogueOption {
+    /// The position of this option within its option set, used to select it.
+    pub index: usize,
+
+    /// The ID of the line containing the option text.
+    pub line_id: String,
+
+    /// Values to substitute into the option text.
+    pub substitutions: Vec<String>,
+
+    /// Whether the option can be selected.
+    pub enabled: bool,
+
+    /// The label that will be jumped to if this option is selected.
+    pub target: String,
+}
+
+/// An event produced by advancing a [`Dialogue`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DialogueEvent {
+    NodeStart {
+        name: String,
+    },
+    Line {
+        line_id: String,
+        substitutions: Vec<String>,
+    },
+    /// A set of options that the player must choose between with [`Dialogue::select_option`]
+    /// before the dialogue can continue.
+    Options(Vec<DialogueOption>),
+    Command(String),
+    NodeComplete {
+        name: String,
+    },
+    DialogueComplete,
+}
+
+#[derive(Error, Debug)]
+pub enum DialogueError {
+    #[error("no node named '{0}' exists in the story")]
+    UnknownNode(String),
+
+    #[error("the dialogue is not running")]
+    NotRunning,
+
+    #[error("the dialogue is waiting for an option to be selected")]
+    AwaitingSelection,
+
+    #[error("the story has no text for locale '{0}'")]
+    UnknownLocale(String),
+
+    #[error("the story has no text for line '{0}'")]
+    UnknownLine(String),
+
+    #[error("invalid markup in line '{0}'")]
+    Markup(String, #[source] MarkupError),
+
+    #[error(transparent)]
+    Option(#[from] OptionError),
+
+    #[error(transparent)]
+    Runner(#[from] StoryRunnerError),
+}
+
+/// A running conversation through a [Story].
+///
+/// [`Dialogue`] owns the position within the story and its variables, and collects the raw
+/// [`StoryEvent`]s produced by the [`StoryRunner`] into higher-level [`DialogueEvent`]s.
+pub struct Dialogue<'s, V = HashMap<String, Value>> {
+    runner: &'s StoryRunner,
+    story: &'s Story,
+    checkpoint: Option<StoryCheckpoint<'s>>,
+    variables: V,
+
+    /// The locale lines are shown in, or [None] for the base locale of the story.
+    locale: Option<String>,
+}
+
+impl<'s, V: VariableStore> Dialogue<'s, V> {
+    #[must_use]
+    pub const fn new(runner: &'s StoryRunner, story: &'s Story, variables: V) -> Self {
+        Self {
+            runner,
+            story,
+            checkpoint: None,
+            variables,
+            locale: None,
+        }
+    }
+
+    /// Start running the dialogue from the beginning of the node named by [name], abandoning
+    /// any previous position.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if there is no node with the given name.
+    pub fn start(&mut self, name: &str) -> Result<(), DialogueError> {
+        let checkpoint = self
+            .story
+            .checkpoint_at(name)
+            .ok_or_else(|| DialogueError::UnknownNode(name.to_string()))?;
+
+        self.resume(checkpoint);
+        Ok(())
+    }
+
+    /// Continue running the dialogue from the given [checkpoint].
+    ///
+    /// If the checkpoint is waiting for an option to be selected, its options are available
+    /// from [`Dialogue::options`] so they can be presented again.
+    pub fn resume(&mut self, checkpoint: StoryCheckpoint<'s>) {
+        self.checkpoint = Some(checkpoint);
+    }
+
+    /// Advance the dialogue until the next [`DialogueEvent`].
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the dialogue is not running, is waiting for an option to be selected, or
+    /// the story could not be advanced. A dialogue that fails to advance stays where it was, so
+    /// it can be advanced again once the cause of the error has been dealt with.
+    pub fn advance(&mut self) -> Result<DialogueEvent, DialogueError> {
+        self.advance_with_user_data(&mut ())
+    }
+
+    /// Advance the dialogue until the next [`DialogueEvent`], making [`user_data`] available to
+    /// functions and commands.
+    ///
+    /// # Errors
+    ///
+    /// See [`Dialogue::advance`].
+    pub fn advance_with_user_data(
+        &mut self,
+        user_data: &mut dyn Any,
+    ) -> Result<DialogueEvent, DialogueError> {
+        if matches!(&self.checkpoint, Some(checkpoint) if checkpoint.is_awaiting_selection()) {
+            return Err(DialogueError::AwaitingSelection);
+        }
+
+        loop {
+            let checkpoint = self.checkpoint.clone().ok_or(DialogueError::NotRunning)?;
+            let (checkpoint, event) = self.runner.step_with_user_data(
+                self.story,
+                checkpoint,
+                &mut self.variables,
+                user_data,
+            )?;
+
+            self.checkpoint = Some(checkpoint);
+
+            let event = match event {
+                StoryEvent::NodeStart { name } => DialogueEvent::NodeStart { name },
+                StoryEvent::AddOption { .. } => continue,
+                StoryEvent::ShowOptions => DialogueEvent::Options(self.options()),
+                StoryEvent::ShowLine { key, substitutions } => DialogueEvent::Line {
+                    line_id: key,
+                    substitutions,
+                },
+                StoryEvent::Command(command) => DialogueEvent::Command(command),
+                StoryEvent::NodeComplete { name } => DialogueEvent::NodeComplete { name },
+                StoryEvent::DialogueComplete => {
+                    self.checkpoint = None;
+                    DialogueEvent::DialogueComplete
+                }
+                #[allow(deprecated)]
+                StoryEvent::Started | StoryEvent::Complete => continue,
+            };
+
+            return Ok(event);
+        }
+    }
+
+    /// Select the option with the given [index] from the most recent [`DialogueEvent::Options`].
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the dialogue is not waiting for a selection, or the option with the
+    /// given index does not exist or is disabled.
+    pub fn select_option(&mut self, index: usize) -> Result<(), DialogueError> {
+        let checkpoint = self.checkpoint.as_mut().ok_or(DialogueError::NotRunning)?;
+        checkpoint.select_option_at(index)?;
+
+        Ok(())
+    }
+
+    /// The options waiting for one to be selected with [`Dialogue::select_option`], which are
+    /// empty unless the dialogue is waiting for a selection.
+    ///
+    /// These are the options of the most recent [`DialogueEvent::Options`], or of the
+    /// checkpoint given to [`Dialogue::resume`].
+    #[must_use]
+    pub fn options(&self) -> Vec<DialogueOption> {
+        let Some(checkpoint) = &self.checkpoint else {
+            return vec![];
+        };
+
+        if !checkpoint.is_awaiting_selection() {
+            return vec![];
+        }
+
+        checkpoint
+            .offered_options()
+            .iter()
+            .enumerate()
+            .map(|(index, option)| DialogueOption {
+                index,
+                line_id: option.key.clone(),
+                substitutions: option.substitutions.clone(),
+                enabled: option.enabled,
+                target: option.target.clone(),
+            })
+            .collect()
+    }
+
+    /// Whether the dialogue has been started and has not yet completed.
+    #[must_use]
+    pub const fn is_running(&self) -> bool {
+        self.checkpoint.is_some()
+    }
+
+    /// The point in the story the dialogue will continue from, if it is running.
+    #[must_use]
+    pub const fn checkpoint(&self) -> Option<&StoryCheckpoint<'s>> {
+        self.checkpoint.as_ref()
+    }
+
+    /// The locale lines are shown in, if the story has been localized.
+    #[must_use]
+    pub fn locale(&self) -> Option<&str> {
+        self.locale.as_deref().or_else(|| {
+            self.story
+                .localization()
+                .map(|localization| localization.base_locale())
+        })
+    }
+
+    /// Show lines in [locale] from now on. This can be changed at any point, including while the
+    /// dialogue is running.
+    ///
+    /// The locale does not need text of its own, as long as it can fall back to a locale that
+    /// does. Its plural rules are used for replacement markers either way.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the story has no text for the given locale or any of its fallbacks.
+    pub fn set_locale(&mut self, locale: &str) -> Result<(), DialogueError> {
+        match self.story.localization() {
+            Some(localization) if localization.supports(locale) => {
+                self.locale = Some(locale.to_string());
+                Ok(())
+            }
+            _ => Err(DialogueError::UnknownLocale(locale.to_string())),
+        }
+    }
+
+    /// Get the text of the line identified by [`line_id`] in the current locale, with its
+    /// [substitutions] applied.
+    pub fn line_text(&self, line_id: &str, substitutions: &[String]) -> Option<String> {
+        let localization = self.story.localization()?;
+        let locale = self
+            .locale
+            .as_deref()
+            .unwrap_or_else(|| localization.base_locale());
+
+        localization.resolve(locale, line_id, substitutions)
+    }
+
+    /// Get the text of the line identified by [`line_id`] in the current locale with its
+    /// [substitutions] applied and its markup parsed, evaluating replacement markers with the
+    /// plural rules of the current locale.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the story has no text for the line, or the text has invalid markup.
+    pub fn parse_line(
+        &self,
+        line_id: &str,
+        substitutions: &[String],
+    ) -> Result<MarkupParseResult, DialogueError> {
+        let text = self
+            .line_text(line_id, substitutions)
+            .ok_or_else(|| DialogueError::UnknownLine(line_id.to_string()))?;
+
+        parse_localized(&text, self.locale().unwrap_or_default())
+            .map_err(|error| DialogueError::Markup(line_id.to_string(), error))
+    }
+
+    /// Get the line identified by [`line_id`] ready to be shown in the current locale, with its
+    /// speaker separated from its text.
+    ///
+    /// # Errors
+    ///
+    /// See [`Dialogue::parse_line`].
+    pub fn resolve_line(
+        &self,
+        line_id: &str,
+        substitutions: &[String],
+    ) -> Result<ResolvedLine, DialogueError> {
+        let markup = self.parse_line(line_id, substitutions)?;
+        let tags = self
+            .story
+            .localization()
+            .map(|localization| localization.tags(line_id).to_vec())
+            .unwrap_or_default();
+
+        Ok(ResolvedLine::new(line_id, &markup, tags))
+    }
+
+    #[must_use]
+    pub const fn story(&self) -> &'s Story {
+        self.story
+    }
+
+    #[must_use]
+    pub const fn variables(&self) -> &V {
+        &self.variables
+    }
+
+    pub fn variables_mut(&mut self) -> &mut V {
+        &mut self.variables
+    }
+
+    /// Stop the dialogue and return ownership of its variables.
+    pub fn into_variables(self) -> V {
+        self.variables
+    }
+}
@@ -1,16 +1,44 @@
 use std::collections::HashMap;
 use std::fs::read;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use prost::{DecodeError, Message};
 use thiserror::Error;
 
+use crate::line::Localization;
 use crate::model::{Node, Program, Value};
-use crate::runner::StoryCheckpoint;
+use crate::runner::{CheckpointError, InstructionError, SavedCheckpoint, StoryCheckpoint};
+use crate::variables::{visit_count_var_name, VariableStore};
+
+mod compiled;
+mod graph;
+mod validate;
+
+pub(crate) use compiled::{CompiledNode, Op, StringTable, Symbol, Target};
+pub use graph::{Edge, EdgeKind, NodeGraph};
+pub use validate::{Diagnostic, DiagnosticKind};
 
 #[derive(Debug)]
 pub struct Story {
-    program: Program,
+    /// Identifies this story among every story built by the process, so that runners can cache
+    /// what they resolve against it.
+    id: u64,
+
+    /// Every node in the story, in the lowered form that is executed by the runner.
+    nodes: Vec<CompiledNode>,
+
+    /// Index into [nodes] of each node by name.
+    node_indices: HashMap<String, usize>,
+
+    /// Strings referenced by the lowered instructions of [nodes].
+    strings: StringTable,
+
+    /// The initial values of variables declared by the program.
+    initial_values: HashMap<String, Value>,
+
+    /// The text of the story's lines in each locale, if it has been provided.
+    localization: Option<Localization>,
 }
 
 impl Story {
@@ -18,24 +46,152 @@ impl Story {
     where
         S: AsRef<str>,
     {
-        self.program
-            .initial_values
-            .get(name.as_ref())
-            .map(|operand| operand.value.as_ref().expect("operand must have a value"))
+        self.initial_values.get(name.as_ref())
+    }
+
+    /// The text of the story's lines in each locale, if it was given to the [Builder].
+    #[must_use]
+    pub const fn localization(&self) -> Option<&Localization> {
+        self.localization.as_ref()
     }
 
     pub fn node<S>(&self, name: S) -> Option<&Node>
     where
         S: AsRef<str>,
     {
-        self.program.nodes.get(name.as_ref())
+        self.compiled_node(name).map(|node| &node.source)
+    }
+
+    /// Iterate over every node in this story, in order of their names.
+    pub fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.nodes.iter().map(|node| &node.source)
+    }
+
+    /// Get the tags of the node named by [name].
+    pub fn node_tags<S>(&self, name: S) -> Option<&[String]>
+    where
+        S: AsRef<str>,
+    {
+        self.node(name).map(|node| node.tags.as_slice())
     }
 
-    pub fn checkpoint_at<S>(&self, name: S) -> Option<StoryCheckpoint>
+    /// Get the value of the first header with the given [key] in the node named by [name].
+    pub fn node_header<S, K>(&self, name: S, key: K) -> Option<&str>
     where
         S: AsRef<str>,
+        K: AsRef<str>,
     {
-        self.node(name).map(StoryCheckpoint::new)
+        self.node(name)?
+            .headers
+            .iter()
+            .find(|header| header.key == key.as_ref())
+            .map(|header| header.value.as_str())
+    }
+
+    /// Iterate over every node in this story that has the given [tag], in order of their names.
+    pub fn nodes_with_tag<'a>(&'a self, tag: &'a str) -> impl Iterator<Item = &'a Node> + 'a {
+        self.nodes()
+            .filter(move |node| node.tags.iter().any(|t| t == tag))
+    }
+
+    /// Create a checkpoint at the start of the node named [name], with a random number generator
+    /// seeded differently on each run.
+    pub fn checkpoint_at<S>(&self, name: S) -> Option<StoryCheckpoint<'_>>
+    where
+        S: AsRef<str>,
+    {
+        self.compiled_node(name).map(StoryCheckpoint::new)
+    }
+
+    pub(crate) fn compiled_node<S>(&self, name: S) -> Option<&CompiledNode>
+    where
+        S: AsRef<str>,
+    {
+        self.node_indices
+            .get(name.as_ref())
+            .map(|index| &self.nodes[*index])
+    }
+
+    pub(crate) fn compiled_nodes(&self) -> &[CompiledNode] {
+        &self.nodes
+    }
+
+    pub(crate) fn compiled_node_at(&self, index: usize) -> &CompiledNode {
+        &self.nodes[index]
+    }
+
+    pub(crate) const fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The number of distinct strings referenced by the story's instructions, which is more
+    /// than the index of any [Symbol] in the story.
+    pub(crate) fn string_count(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub(crate) fn string(&self, symbol: Symbol) -> &str {
+        self.strings.get(symbol)
+    }
+
+    /// Resolve a [`SavedCheckpoint`] back into a [`StoryCheckpoint`] within this story.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the node the checkpoint was saved in no longer exists or the saved
+    /// instruction offset is no longer valid.
+    pub fn restore(&self, saved: &SavedCheckpoint) -> Result<StoryCheckpoint<'_>, CheckpointError> {
+        saved.resolve(self)
+    }
+
+    /// Get the number of times the node named by [name] has been visited.
+    pub fn visit_count(&self, variables: &dyn VariableStore, name: &str) -> f32 {
+        let var_name = visit_count_var_name(name);
+
+        match variables
+            .get(&var_name)
+            .or_else(|| self.initial_value(&var_name))
+        {
+            Some(Value::FloatValue(count)) => *count,
+            _ => 0.0,
+        }
+    }
+
+    /// Iterate over the names of all nodes in this story alongside their visit counts, in order
+    /// of their names.
+    pub fn visit_counts<'a>(
+        &'a self,
+        variables: &'a dyn VariableStore,
+    ) -> impl Iterator<Item = (&'a str, f32)> + 'a {
+        self.nodes
+            .iter()
+            .map(move |node| (node.name(), self.visit_count(variables, node.name())))
+    }
+
+    /// Reset the visit count of the node named by [name].
+    pub fn reset_visit_count(&self, variables: &mut dyn VariableStore, name: &str) {
+        variables.set(&visit_count_var_name(name), Value::FloatValue(0.0));
+    }
+
+    /// Reset the visit counts of every node in this story.
+    pub fn reset_visit_counts(&self, variables: &mut dyn VariableStore) {
+        for node in &self.nodes {
+            self.reset_visit_count(variables, node.name());
+        }
+    }
+
+    /// Record a visit to [node], unless the node's compiled instructions already track their
+    /// own visits.
+    pub(crate) fn record_visit(&self, variables: &mut dyn VariableStore, node: &CompiledNode) {
+        if node.tracks_visits {
+            return;
+        }
+
+        let count = self.visit_count(variables, node.name());
+        variables.set(
+            &visit_count_var_name(node.name()),
+            Value::FloatValue(count + 1.0),
+        );
     }
 }
 
@@ -60,11 +216,15 @@ pub enum BuilderError {
 
     #[error("failed to decode program file")]
     Protocol(#[from] DecodeError),
+
+    #[error("invalid instruction #{1} in node '{0}'")]
+    Instruction(String, usize, #[source] InstructionError),
 }
 
 #[derive(Default)]
 pub struct Builder {
     sources: Vec<Source>,
+    localization: Option<Localization>,
 }
 
 impl Builder {
@@ -75,8 +235,15 @@ impl Builder {
     }
 
     #[must_use]
-    pub fn add_program(mut self, program: Program) -> Self {
-        self.sources.push(Source::Program(program));
+    pub fn add_program<P: Into<Program>>(mut self, program: P) -> Self {
+        self.sources.push(Source::Program(program.into()));
+        self
+    }
+
+    /// Attach the text of the story's lines in each locale to the built [Story].
+    #[must_use]
+    pub fn localization(mut self, localization: Localization) -> Self {
+        self.localization = Some(localization);
         self
     }
 
@@ -84,8 +251,8 @@ impl Builder {
     ///
     /// # Errors
     ///
-    /// Returns `Err` if a program could not be loaded or combining all
-    /// available programs would result in conflicts/ambiguities.
+    /// Returns `Err` if a program could not be loaded, contains instructions that could not be
+    /// decoded, or combining all available programs would result in conflicts/ambiguities.
     pub fn build(self) -> Result<Story, BuilderError> {
         fn merge<V>(
             dest: &mut HashMap<String, V>,
@@ -123,6 +290,39 @@ impl Builder {
             )?;
         }
 
-        Ok(Story { program: root })
+        let mut sources: Vec<_> = root.nodes.into_iter().collect();
+        sources.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let node_indices: HashMap<String, usize> = sources
+            .iter()
+            .enumerate()
+            .map(|(index, (name, _))| (name.clone(), index))
+            .collect();
+
+        let mut strings = StringTable::default();
+        let nodes = sources
+            .into_iter()
+            .map(|(name, node)| {
+                CompiledNode::compile(node, &node_indices, &mut strings)
+                    .map_err(|(pc, error)| BuilderError::Instruction(name, pc, error))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let initial_values = root
+            .initial_values
+            .iter()
+            .map(|(name, operand)| (name.clone(), Value::from(operand)))
+            .collect();
+
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        Ok(Story {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            nodes,
+            node_indices,
+            strings,
+            initial_values,
+            localization: self.localization,
+        })
     }
 }
@@ -0,0 +1,253 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::model::{Instruction, Node, OpCode, Operands};
+use crate::runner::InstructionError;
+use crate::variables::visit_count_var_name;
+
+/// A handle to a string interned in a [`StringTable`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct Symbol(u32);
+
+impl Symbol {
+    /// The position of the symbol's string in its [`StringTable`], which is less than the
+    /// table's length.
+    pub(crate) const fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// Deduplicated storage for the strings referenced by a story's instructions.
+#[derive(Debug, Default)]
+pub(crate) struct StringTable {
+    strings: Vec<String>,
+    symbols: HashMap<String, Symbol>,
+}
+
+impl StringTable {
+    pub(crate) fn intern(&mut self, value: &str) -> Symbol {
+        if let Some(symbol) = self.symbols.get(value) {
+            return *symbol;
+        }
+
+        let symbol = Symbol(self.strings.len() as u32);
+        self.strings.push(value.to_string());
+        self.symbols.insert(value.to_string(), symbol);
+        symbol
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub(crate) fn get(&self, symbol: Symbol) -> &str {
+        &self.strings[symbol.0 as usize]
+    }
+}
+
+/// The destination of a jump to a label within the same node.
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Target {
+    Offset(usize),
+    /// A label that does not exist in the node, reported when the jump is taken.
+    ///
+    /// Like the upstream runtime, a story with such a jump still builds, since the jump may sit
+    /// on a path that is never taken. [`Story::validate`](super::Story::validate) reports them
+    /// ahead of time as [`DiagnosticKind::UnknownLabel`](super::DiagnosticKind::UnknownLabel).
+    Unresolved(Symbol),
+}
+
+/// An instruction lowered from its [Instruction] encoding, with operands decoded up front.
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Op {
+    JumpTo(Target),
+    Jump,
+    RunLine {
+        key: Symbol,
+        substitutions: usize,
+    },
+    RunCommand {
+        text: Symbol,
+        substitutions: usize,
+    },
+    AddOption {
+        key: Symbol,
+        target: Symbol,
+        substitutions: usize,
+        has_condition: bool,
+    },
+    ShowOptions,
+    PushString(Symbol),
+    PushFloat(f32),
+    PushBool(bool),
+    PushNull,
+    JumpIfFalse(Target),
+    Pop,
+    CallFunc(Symbol),
+    PushVariable(Symbol),
+    StoreVariable(Symbol),
+    Stop,
+    /// Run the node named by the value on top of the stack, or with `Some`, the node at the
+    /// given index without touching the stack.
+    ///
+    /// A `PushString` of a known node name followed by `RunNode` is lowered with `Some` in
+    /// place of the `PushString`, so the name is never pushed. The `RunNode` itself keeps
+    /// `None`, for checkpoints saved before the lowering that resume at it.
+    RunNode(Option<usize>),
+}
+
+/// A [Node] alongside the lowered form of its instructions.
+#[derive(Debug)]
+pub(crate) struct CompiledNode {
+    pub(crate) source: Node,
+    pub(crate) ops: Vec<Op>,
+
+    /// The offset of each label in [source], sorted by name.
+    labels: Vec<(String, usize)>,
+
+    /// Whether the node's instructions already update its own visit count.
+    pub(crate) tracks_visits: bool,
+}
+
+impl CompiledNode {
+    pub(crate) fn name(&self) -> &str {
+        &self.source.name
+    }
+
+    /// Get the offset of the label named by [name].
+    pub(crate) fn label(&self, name: &str) -> Option<usize> {
+        self.labels
+            .binary_search_by(|(label, _)| label.as_str().cmp(name))
+            .ok()
+            .map(|index| self.labels[index].1)
+    }
+
+    /// Lower the instructions of [source].
+    ///
+    /// Node names and labels given to `RunNode` and `Jump` by a constant are resolved using
+    /// [node_indices] and the labels of [source], unless an instruction jumps between the two.
+    pub(crate) fn compile(
+        source: Node,
+        node_indices: &HashMap<String, usize>,
+        strings: &mut StringTable,
+    ) -> Result<Self, (usize, InstructionError)> {
+        let mut ops = source
+            .instructions
+            .iter()
+            .enumerate()
+            .map(|(pc, instruction)| lower(&source, instruction, strings).map_err(|e| (pc, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut labels: Vec<(String, usize)> = source
+            .labels
+            .iter()
+            .map(|(name, offset)| (name.clone(), *offset as usize))
+            .collect();
+        labels.sort_unstable();
+
+        let label_targets: HashSet<usize> = labels.iter().map(|(_, offset)| *offset).collect();
+
+        for pc in 1..ops.len() {
+            let Op::PushString(name) = ops[pc - 1] else {
+                continue;
+            };
+
+            if label_targets.contains(&pc) {
+                continue;
+            }
+
+            let name = strings.get(name);
+            let resolved = match ops[pc] {
+                Op::RunNode(None) => node_indices
+                    .get(name)
+                    .map(|index| Op::RunNode(Some(*index))),
+                Op::Jump => labels
+                    .binary_search_by(|(label, _)| label.as_str().cmp(name))
+                    .ok()
+                    .map(|index| Op::JumpTo(Target::Offset(labels[index].1))),
+                _ => None,
+            };
+
+            if let Some(op) = resolved {
+                ops[pc - 1] = op;
+            }
+        }
+
+        let visit_var = visit_count_var_name(&source.name);
+        let tracks_visits = ops
+            .iter()
+            .any(|op| matches!(op, Op::StoreVariable(name) if strings.get(*name) == visit_var));
+
+        Ok(Self {
+            source,
+            ops,
+            labels,
+            tracks_visits,
+        })
+    }
+}
+
+fn lower(
+    node: &Node,
+    instruction: &Instruction,
+    strings: &mut StringTable,
+) -> Result<Op, InstructionError> {
+    let operands = &instruction.operands;
+    let opcode = OpCode::from_i32(instruction.opcode)
+        .ok_or(InstructionError::InvalidInstruction(instruction.opcode))?;
+
+    let symbol = |strings: &mut StringTable, index: usize| -> Result<Symbol, InstructionError> {
+        Ok(strings.intern(&operands.at::<String>(index)?))
+    };
+
+    let count = |index: usize| -> Result<usize, InstructionError> {
+        Ok(if operands.len() > index {
+            operands.at::<f32>(index)? as usize
+        } else {
+            0
+        })
+    };
+
+    let op = match opcode {
+        OpCode::JumpTo | OpCode::JumpIfFalse => {
+            let label = operands.at::<String>(0)?;
+            let target = node.resolve_label(&label).map_or_else(
+                |_| Target::Unresolved(strings.intern(&label)),
+                Target::Offset,
+            );
+
+            if opcode == OpCode::JumpTo {
+                Op::JumpTo(target)
+            } else {
+                Op::JumpIfFalse(target)
+            }
+        }
+        OpCode::Jump => Op::Jump,
+        OpCode::RunLine => Op::RunLine {
+            key: symbol(strings, 0)?,
+            substitutions: count(1)?,
+        },
+        OpCode::RunCommand => Op::RunCommand {
+            text: symbol(strings, 0)?,
+            substitutions: count(1)?,
+        },
+        OpCode::AddOption => Op::AddOption {
+            key: symbol(strings, 0)?,
+            target: symbol(strings, 1)?,
+            substitutions: count(2)?,
+            has_condition: operands.len() > 3 && operands.at::<bool>(3)?,
+        },
+        OpCode::ShowOptions => Op::ShowOptions,
+        OpCode::PushString => Op::PushString(symbol(strings, 0)?),
+        OpCode::PushFloat => Op::PushFloat(operands.at::<f32>(0)?),
+        OpCode::PushBool => Op::PushBool(operands.at::<bool>(0)?),
+        OpCode::PushNull => Op::PushNull,
+        OpCode::Pop => Op::Pop,
+        OpCode::CallFunc => Op::CallFunc(symbol(strings, 0)?),
+        OpCode::PushVariable => Op::PushVariable(symbol(strings, 0)?),
+        OpCode::StoreVariable => Op::StoreVariable(symbol(strings, 0)?),
+        OpCode::Stop => Op::Stop,
+        OpCode::RunNode => Op::RunNode(None),
+    };
+
+    Ok(op)
+}
@@ -0,0 +1,237 @@
+use std::collections::{HashSet, VecDeque};
+use std::fmt::Write;
+
+use super::{Op, Story};
+
+/// How one node leads to another in a [`NodeGraph`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum EdgeKind {
+    /// The node runs the other node by name.
+    Run,
+    /// The node offers an option that leads directly to the other node.
+    Option,
+}
+
+impl EdgeKind {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Run => "run",
+            Self::Option => "option",
+        }
+    }
+}
+
+/// A transition from one node to another found in the instructions of a [Story].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Edge {
+    pub from: String,
+    pub to: String,
+    pub kind: EdgeKind,
+
+    /// The offset of the instruction in [from] that makes the transition.
+    pub pc: usize,
+}
+
+/// The nodes of a [Story] and the transitions between them that can be determined without
+/// running it.
+///
+/// Nodes that are run using a name computed at runtime can't be followed, so their edges are
+/// missing from the graph.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NodeGraph {
+    nodes: Vec<String>,
+    edges: Vec<Edge>,
+}
+
+impl Story {
+    /// Build the graph of transitions between the nodes of this story.
+    #[must_use]
+    pub fn graph(&self) -> NodeGraph {
+        let mut edges = vec![];
+
+        for node in &self.nodes {
+            for (pc, op) in node.ops.iter().enumerate() {
+                let (to, kind) = match *op {
+                    // Edges are reported at the `RunNode` instruction, even when the node it runs
+                    // was resolved in place of the `PushString` before it.
+                    Op::RunNode(None) => match pc.checked_sub(1).map(|prev| node.ops[prev]) {
+                        Some(Op::PushString(name)) => (self.string(name), EdgeKind::Run),
+                        Some(Op::RunNode(Some(index))) => (self.nodes[index].name(), EdgeKind::Run),
+                        _ => continue,
+                    },
+                    Op::AddOption { target, .. } => {
+                        let target = self.string(target);
+                        if node.source.labels.contains_key(target)
+                            || !self.node_indices.contains_key(target)
+                        {
+                            continue;
+                        }
+
+                        (target, EdgeKind::Option)
+                    }
+                    _ => continue,
+                };
+
+                edges.push(Edge {
+                    from: node.name().to_string(),
+                    to: to.to_string(),
+                    kind,
+                    pc,
+                });
+            }
+        }
+
+        NodeGraph {
+            nodes: self
+                .nodes
+                .iter()
+                .map(|node| node.name().to_string())
+                .collect(),
+            edges,
+        }
+    }
+}
+
+impl NodeGraph {
+    /// The names of every node in the story, in sorted order.
+    #[must_use]
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    #[must_use]
+    pub fn edges(&self) -> &[Edge] {
+        &self.edges
+    }
+
+    /// Iterate over the edges leaving the node named by [name].
+    pub fn edges_from<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Edge> + 'a {
+        self.edges.iter().filter(move |edge| edge.from == name)
+    }
+
+    /// Find the nodes that can't be reached by following edges from any of the nodes named in
+    /// [starts].
+    #[must_use]
+    pub fn unreachable_from<S: AsRef<str>>(&self, starts: &[S]) -> Vec<&str> {
+        let mut reached: HashSet<&str> = HashSet::new();
+        let mut queue: VecDeque<&str> = starts.iter().map(AsRef::as_ref).collect();
+
+        while let Some(name) = queue.pop_front() {
+            if reached.insert(name) {
+                queue.extend(self.edges_from(name).map(|edge| edge.to.as_str()));
+            }
+        }
+
+        self.nodes
+            .iter()
+            .map(String::as_str)
+            .filter(|name| !reached.contains(name))
+            .collect()
+    }
+
+    /// Find the nodes that don't lead to any other node.
+    #[must_use]
+    pub fn dead_ends(&self) -> Vec<&str> {
+        self.nodes
+            .iter()
+            .map(String::as_str)
+            .filter(|name| self.edges_from(name).next().is_none())
+            .collect()
+    }
+
+    /// Render this graph in the Graphviz DOT language. Edges for options are dashed.
+    #[must_use]
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph story {\n");
+
+        for node in &self.nodes {
+            let _ = writeln!(dot, "    {};", quote_dot(node));
+        }
+
+        for edge in &self.edges {
+            let style = match edge.kind {
+                EdgeKind::Run => "",
+                EdgeKind::Option => " [style=dashed]",
+            };
+
+            let _ = writeln!(
+                dot,
+                "    {} -> {}{style};",
+                quote_dot(&edge.from),
+                quote_dot(&edge.to)
+            );
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Render this graph as a JSON object with `nodes` and `edges` arrays.
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        let nodes: Vec<_> = self.nodes.iter().map(|node| quote_json(node)).collect();
+        let edges: Vec<_> = self
+            .edges
+            .iter()
+            .map(|edge| {
+                format!(
+                    r#"{{"from":{},"to":{},"kind":"{}","pc":{}}}"#,
+                    quote_json(&edge.from),
+                    quote_json(&edge.to),
+                    edge.kind.name(),
+                    edge.pc
+                )
+            })
+            .collect();
+
+        format!(
+            r#"{{"nodes":[{}],"edges":[{}]}}"#,
+            nodes.join(","),
+            edges.join(",")
+        )
+    }
+}
+
+/// Quote [value] as a DOT string literal.
+///
+/// DOT has no escape for control characters, so only newlines are escaped and any others are
+/// written as they are.
+fn quote_dot(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+/// Quote [value] as a JSON string literal.
+fn quote_json(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
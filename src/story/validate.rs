@@ -0,0 +1,215 @@
+use std::collections::HashSet;
+
+use thiserror::Error;
+
+use super::{CompiledNode, Op, Story, Target};
+
+/// A problem found in a [Story] by [`Story::validate`].
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+#[error("{kind} at #{pc} in node '{node}'")]
+pub struct Diagnostic {
+    pub node: String,
+    pub pc: usize,
+    pub kind: DiagnosticKind,
+}
+
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    #[error("jump to unknown label '{0}'")]
+    UnknownLabel(String),
+
+    #[error("option leads to unknown label '{0}'")]
+    UnknownOptionTarget(String),
+
+    #[error("no node named '{0}' exists")]
+    UnknownNode(String),
+
+    #[error("variable '{0}' has no initial value and is never stored")]
+    UndefinedVariable(String),
+
+    #[error("instruction needs {needed} values on the stack but only {available} are available")]
+    StackUnderflow { needed: usize, available: usize },
+}
+
+impl Story {
+    /// Check every node in this story for problems that would otherwise only be found when the
+    /// affected instructions run.
+    ///
+    /// All problems are reported, ordered by node name and then by instruction offset.
+    ///
+    /// The stack is only checked for values that are popped without having been pushed.
+    /// Stories compiled by Yarn Spinner leave the condition of an `if` on the stack when its
+    /// body runs, so paths that merge with different numbers of values on the stack, and values
+    /// left on the stack when a node stops or runs another node, are expected and not reported.
+    #[must_use]
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let stored: HashSet<&str> = self
+            .nodes
+            .iter()
+            .flat_map(|node| &node.ops)
+            .filter_map(|op| match op {
+                Op::StoreVariable(name) => Some(self.string(*name)),
+                _ => None,
+            })
+            .collect();
+
+        let mut diagnostics = vec![];
+        for node in &self.nodes {
+            let mut found = vec![];
+            self.check_operands(node, &stored, &mut found);
+            self.check_stack(node, &mut found);
+
+            found.sort_by_key(|(pc, _)| *pc);
+            diagnostics.extend(found.into_iter().map(|(pc, kind)| Diagnostic {
+                node: node.name().to_string(),
+                pc,
+                kind,
+            }));
+        }
+
+        diagnostics
+    }
+
+    fn check_operands(
+        &self,
+        node: &CompiledNode,
+        stored: &HashSet<&str>,
+        found: &mut Vec<(usize, DiagnosticKind)>,
+    ) {
+        for (pc, op) in node.ops.iter().enumerate() {
+            let kind = match *op {
+                Op::JumpTo(Target::Unresolved(label))
+                | Op::JumpIfFalse(Target::Unresolved(label)) => {
+                    DiagnosticKind::UnknownLabel(self.string(label).to_string())
+                }
+                Op::AddOption { target, .. } => {
+                    // Options may also lead directly to a node, which is run with `RunNode`.
+                    let target = self.string(target);
+                    if node.source.labels.contains_key(target)
+                        || self.node_indices.contains_key(target)
+                    {
+                        continue;
+                    }
+
+                    DiagnosticKind::UnknownOptionTarget(target.to_string())
+                }
+                Op::RunNode(None) => {
+                    let Some(Op::PushString(name)) = pc.checked_sub(1).map(|prev| node.ops[prev])
+                    else {
+                        continue;
+                    };
+
+                    let name = self.string(name);
+                    if self.node_indices.contains_key(name) {
+                        continue;
+                    }
+
+                    DiagnosticKind::UnknownNode(name.to_string())
+                }
+                Op::PushVariable(name) => {
+                    let name = self.string(name);
+                    if self.initial_values.contains_key(name) || stored.contains(name) {
+                        continue;
+                    }
+
+                    DiagnosticKind::UndefinedVariable(name.to_string())
+                }
+                _ => continue,
+            };
+
+            found.push((pc, kind));
+        }
+    }
+
+    /// Follow every path through [node], tracking the fewest values that may be on the stack
+    /// before each instruction.
+    ///
+    /// Paths through calls whose argument count is not a constant can't be followed and are
+    /// skipped.
+    fn check_stack(&self, node: &CompiledNode, found: &mut Vec<(usize, DiagnosticKind)>) {
+        // A `Jump` continues at whichever option was selected, with its target on the stack.
+        let option_targets: Vec<usize> = node
+            .ops
+            .iter()
+            .filter_map(|op| match op {
+                Op::AddOption { target, .. } => node.label(self.string(*target)),
+                _ => None,
+            })
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+
+        let mut depths: Vec<Option<usize>> = vec![None; node.ops.len()];
+        let mut pending = vec![(0, 0)];
+
+        while let Some((pc, depth)) = pending.pop() {
+            let Some(op) = node.ops.get(pc) else {
+                continue;
+            };
+
+            // Compiled conditionals leave their condition on the stack along the branch that runs
+            // their body, so paths are allowed to merge with different depths. Only the shallowest
+            // needs to be followed to find values that are popped without having been pushed.
+            if matches!(depths[pc], Some(previous) if previous <= depth) {
+                continue;
+            }
+
+            depths[pc] = Some(depth);
+
+            let (pops, pushes) = match *op {
+                Op::JumpTo(_) | Op::Stop | Op::RunNode(Some(_)) => (0, 0),
+                // The target of the selected option is pushed before execution continues.
+                Op::ShowOptions => (0, 1),
+                Op::RunLine { substitutions, .. } | Op::RunCommand { substitutions, .. } => {
+                    (substitutions, 0)
+                }
+                Op::AddOption {
+                    substitutions,
+                    has_condition,
+                    ..
+                } => (substitutions + usize::from(has_condition), 0),
+                Op::PushString(_)
+                | Op::PushFloat(_)
+                | Op::PushBool(_)
+                | Op::PushNull
+                | Op::PushVariable(_) => (0, 1),
+                Op::JumpIfFalse(_) | Op::StoreVariable(_) => (1, 1),
+                Op::Jump | Op::Pop | Op::RunNode(None) => (1, 0),
+                Op::CallFunc(_) => match pc.checked_sub(1).map(|prev| node.ops[prev]) {
+                    Some(Op::PushFloat(count)) => (1 + count as usize, 1),
+                    _ => continue,
+                },
+            };
+
+            if depth < pops {
+                found.push((
+                    pc,
+                    DiagnosticKind::StackUnderflow {
+                        needed: pops,
+                        available: depth,
+                    },
+                ));
+                continue;
+            }
+
+            let depth = depth - pops + pushes;
+            match *op {
+                Op::JumpTo(target) => pending.extend(resolved(target).map(|pc| (pc, depth))),
+                Op::JumpIfFalse(target) => {
+                    pending.push((pc + 1, depth));
+                    pending.extend(resolved(target).map(|pc| (pc, depth)));
+                }
+                Op::Jump => pending.extend(option_targets.iter().map(|pc| (*pc, depth))),
+                Op::Stop | Op::RunNode(_) => {}
+                _ => pending.push((pc + 1, depth)),
+            }
+        }
+    }
+}
+
+const fn resolved(target: Target) -> Option<usize> {
+    match target {
+        Target::Offset(offset) => Some(offset),
+        Target::Unresolved(_) => None,
+    }
+}
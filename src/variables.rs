@@ -1,7 +1,14 @@
 use std::collections::HashMap;
 use std::hash::BuildHasher;
 
-use crate::model::operand::Value;
+use crate::model::Value;
+
+/// Get the name of the internal variable used to track how many times the node named by [name]
+/// has been visited.
+#[must_use]
+pub fn visit_count_var_name(node_name: &str) -> String {
+    format!("$Yarn.Internal.Visiting.{node_name}")
+}
 
 pub trait VariableStore {
     /// Get a reference to the current value of the variable named by [name].
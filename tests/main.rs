@@ -55,8 +55,17 @@ impl From<TestPlan> for Trial {
 
             let mut option_targets = vec![];
             for expected_event in events {
-                let event: StoryEvent;
-                (checkpoint, event) = runner.step(&plan.story, checkpoint, &mut vars)?;
+                let mut event: StoryEvent;
+                loop {
+                    (checkpoint, event) = runner.step(&plan.story, checkpoint, &mut vars)?;
+
+                    if !matches!(
+                        event,
+                        StoryEvent::NodeStart { .. } | StoryEvent::NodeComplete { .. }
+                    ) {
+                        break;
+                    }
+                }
 
                 match expected_event {
                     TestPlanInstruction::ExpectOption(_) => {
@@ -80,7 +89,7 @@ impl From<TestPlan> for Trial {
                     }
                     TestPlanInstruction::SelectOption(option) => {
                         assert_eq!(StoryEvent::ShowOptions, event);
-                        checkpoint.select_option(option_targets.remove(option - 1));
+                        checkpoint.select_option(option_targets.remove(option - 1))?;
                         option_targets.clear();
                     }
                     _ => {}